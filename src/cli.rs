@@ -0,0 +1,58 @@
+//! コマンドライン引数
+//!
+//! `stockmind`(または`stockmind serve`)でサーバーを起動し、
+//! `stockmind config check`で設定の検証だけを行う。
+
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+#[command(name = "stockmind", version, about = "Slackのメンション応答ボット")]
+pub struct Cli {
+    #[command(flatten)]
+    pub overrides: ConfigOverrides,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// サーバーを起動する(既定)
+    Serve,
+    /// 設定を操作する
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// 設定を検証し、有効な設定をシークレットを伏せて表示する
+    Check,
+}
+
+/// 設定ファイル・環境変数より優先する指定
+#[derive(Debug, Clone, Default, Args)]
+pub struct ConfigOverrides {
+    /// 設定ファイルのパス(既定はSTOCKMIND_CONFIGかstockmind.toml)
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// イベントの受信経路(ngrok / http / socket)
+    #[arg(long, global = true)]
+    pub transport: Option<String>,
+
+    /// HTTPで待ち受けるアドレス(例: 0.0.0.0:3000)
+    #[arg(long, global = true)]
+    pub listen: Option<String>,
+
+    /// ログレベル(error / warn / info / debug / trace)
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+
+    /// 既定のモデル
+    #[arg(long, global = true)]
+    pub model: Option<String>,
+}
@@ -0,0 +1,928 @@
+//! 実行時設定ファイル(TOML)
+//!
+//! `--config`か`STOCKMIND_CONFIG`で指定したファイル(既定は`stockmind.toml`)を読み込み、
+//! 環境変数、コマンドライン引数の順に上書きする。既定のパスにファイルがなければ
+//! 環境変数と既定値で動作する。検証では不足・不正な項目をまとめて報告する。
+
+use crate::cli::ConfigOverrides;
+use crate::conversation::ConversationOptions;
+use crate::dedup::DedupOptions;
+use crate::health::HealthOptions;
+use crate::llm::{DEFAULT_MODEL, LLMOptions, ResponseFormat, RetryPolicy};
+use crate::policy::RespondMode;
+use crate::rate_limit::LimitOptions;
+use crate::retrieval::RetrievalOptions;
+use crate::streaming::StreamingOptions;
+use crate::summarize::SummaryOptions;
+use crate::tools::ToolOptions;
+use anyhow::{Context, anyhow, bail};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_CONFIG_PATH: &str = "stockmind.toml";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeConfig {
+    pub slack: SlackConfig,
+    pub server: ServerConfig,
+    pub llm: LlmConfig,
+    pub storage: StorageConfig,
+    pub log: LogConfig,
+    pub limits: LimitOptions,
+    pub dedup: DedupOptions,
+    pub conversation: ConversationOptions,
+    pub retrieval: RetrievalOptions,
+    pub streaming: StreamingOptions,
+    pub summary: SummaryOptions,
+    pub tools: ToolOptions,
+    pub health: HealthOptions,
+    pub models: ModelsConfig,
+    pub generation: GenerationSettings,
+    pub personas: PersonasConfig,
+    pub bot: BotConfig,
+    pub policy: PolicyConfig,
+    pub digests: Vec<DigestConfig>,
+}
+
+/// ログなどに値を出さない設定値(トークンやシークレット)
+#[derive(Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"********\"")
+    }
+}
+
+// Slackとの接続の設定
+//
+// ```toml
+// [slack]
+// transport = "ngrok"     # ngrok / http / socket
+// event_path = "/push"
+// command_path = "/commands"
+// ```
+//
+// トークン類は環境変数(SLACK_SIGNING_SECRET / SLACK_BOT_TOKEN / SLACK_APP_TOKEN)でも指定できる
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SlackConfig {
+    pub signing_secret: Option<Secret>,
+    pub bot_token: Option<Secret>,
+    /// Socket Modeで使うアプリレベルトークン
+    pub app_token: Option<Secret>,
+    pub transport: TransportKind,
+    /// イベントAPIのリクエストURLのパス
+    pub event_path: String,
+    /// スラッシュコマンドのリクエストURLのパス
+    pub command_path: String,
+    /// Socket Modeでapps.connections.openを呼ばずに接続するURL(ローカルの検証用)
+    pub socket_mode_url: Option<String>,
+}
+
+impl Default for SlackConfig {
+    fn default() -> Self {
+        Self {
+            signing_secret: None,
+            bot_token: None,
+            app_token: None,
+            transport: TransportKind::default(),
+            event_path: "/push".to_string(),
+            command_path: "/commands".to_string(),
+            socket_mode_url: None,
+        }
+    }
+}
+
+/// イベントの受信経路
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransportKind {
+    #[default]
+    Ngrok,
+    Http,
+    Socket,
+}
+
+impl FromStr for TransportKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ngrok" => Ok(Self::Ngrok),
+            "http" => Ok(Self::Http),
+            "socket" => Ok(Self::Socket),
+            other => Err(format!(
+                "不明な受信経路です: {} (ngrok, http, socketのいずれか)",
+                other
+            )),
+        }
+    }
+}
+
+// HTTPサーバーの設定
+//
+// ```toml
+// [server]
+// listen = "0.0.0.0:3000"
+// tls_cert = "/etc/stockmind/cert.pem"   # 両方指定するとHTTPS
+// tls_key = "/etc/stockmind/key.pem"
+// ngrok_domain = "example.ngrok.app"     # transport = "ngrok" の場合
+// shutdown_timeout_secs = 30
+// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: String,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub ngrok_domain: Option<String>,
+    /// 終了時に処理中の応答を待つ最大秒数
+    pub shutdown_timeout_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: "127.0.0.1:3000".to_string(),
+            tls_cert: None,
+            tls_key: None,
+            ngrok_domain: None,
+            shutdown_timeout_secs: 30,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    pub fn listen_addr(&self) -> anyhow::Result<SocketAddr> {
+        self.listen
+            .parse()
+            .map_err(|_| anyhow!("[server] listenのアドレスが不正です: {}", self.listen))
+    }
+}
+
+// LLM APIの設定
+//
+// ```toml
+// [llm]
+// api_url = "https://llm.example.com"
+// operator_id = "op_xxxx"
+// embedding_url = "https://llm.example.com/embeddings"
+// embedding_model = "text-embedding-3-small"   # 指定するとベクトル検索を有効にする
+//
+// [llm.retry]
+// max_retries = 3
+// timeout_secs = 120
+// ```
+//
+// api_tokenは環境変数API_TOKENでも指定できる
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LlmConfig {
+    pub api_url: Option<String>,
+    pub operator_id: Option<String>,
+    pub api_token: Option<Secret>,
+    pub embedding_url: Option<String>,
+    pub embedding_model: Option<String>,
+    pub retry: RetryPolicy,
+}
+
+// 保存先の設定
+//
+// ```toml
+// [storage]
+// db_path = "stockmind.db"
+// vector_index_path = "stockmind.vectors"
+// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub db_path: String,
+    pub vector_index_path: String,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            db_path: "stockmind.db".to_string(),
+            vector_index_path: "stockmind.vectors".to_string(),
+        }
+    }
+}
+
+// ログの設定
+//
+// ```toml
+// [log]
+// level = "info"   # error / warn / info / debug / trace
+// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+        }
+    }
+}
+
+impl LogConfig {
+    pub fn max_level(&self) -> anyhow::Result<tracing::Level> {
+        self.level
+            .parse()
+            .map_err(|_| anyhow!("[log] levelが不正です: {}", self.level))
+    }
+}
+
+/// 必須の設定値を取り出す
+pub fn required<'a, T>(value: &'a Option<T>, key: &str) -> anyhow::Result<&'a T> {
+    value
+        .as_ref()
+        .ok_or_else(|| anyhow!("{}が設定されていません", key))
+}
+
+// ボットとしての振る舞いの設定
+//
+// ```toml
+// [bot]
+// respond_to_other_bots = false
+// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BotConfig {
+    /// stockmind以外のボットの発言にも応答するか(ボット同士の応答の連鎖に注意)
+    pub respond_to_other_bots: bool,
+}
+
+// モデルのルーティング設定
+//
+// ```toml
+// [models]
+// default = "google_ai:gemini-2.0-flash-exp"
+// allowed = ["openai:gpt-4o"]
+//
+// [models.workspaces]
+// T0123456 = "openai:gpt-4o"
+//
+// [models.channels]
+// C0123456 = "openai:gpt-4o-mini"
+// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelsConfig {
+    /// ワークスペース・チャンネルの指定がない場合のモデル
+    pub default: String,
+    /// `--model`で指定できるモデル(ルーティングで使うモデルは常に許可される)
+    pub allowed: Vec<String>,
+    /// team_idごとのモデル
+    pub workspaces: HashMap<String, String>,
+    /// チャンネルIDごとのモデル(ワークスペースの指定より優先)
+    pub channels: HashMap<String, String>,
+}
+
+impl Default for ModelsConfig {
+    fn default() -> Self {
+        Self {
+            default: DEFAULT_MODEL.to_string(),
+            allowed: Vec::new(),
+            workspaces: HashMap::new(),
+            channels: HashMap::new(),
+        }
+    }
+}
+
+// 生成パラメータの設定
+//
+// ```toml
+// [generation.default]
+// temperature = 0.7
+// system_prompt = "日本語で簡潔に回答してください。"
+//
+// [generation.channels.C0123456]
+// temperature = 0.2
+// max_tokens = 1024
+// response_format = "json_object"
+// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GenerationSettings {
+    pub default: GenerationConfig,
+    /// チャンネルIDごとの設定。指定した項目だけ`default`を上書きする
+    pub channels: HashMap<String, GenerationConfig>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GenerationConfig {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub stop: Option<Vec<String>>,
+    pub seed: Option<i64>,
+    pub response_format: Option<ResponseFormat>,
+    pub system_prompt: Option<String>,
+}
+
+impl GenerationSettings {
+    /// チャンネルの設定を既定の設定に重ねたものを返す
+    pub fn for_channel(&self, channel: &str) -> GenerationConfig {
+        match self.channels.get(channel) {
+            Some(overrides) => self.default.merged(overrides),
+            None => self.default.clone(),
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        self.default.validate().context("[generation.default]")?;
+        for (channel, config) in &self.channels {
+            config
+                .validate()
+                .with_context(|| format!("[generation.channels.{}]", channel))?;
+        }
+        Ok(())
+    }
+}
+
+impl GenerationConfig {
+    fn merged(&self, overrides: &GenerationConfig) -> GenerationConfig {
+        GenerationConfig {
+            temperature: overrides.temperature.or(self.temperature),
+            top_p: overrides.top_p.or(self.top_p),
+            max_tokens: overrides.max_tokens.or(self.max_tokens),
+            stop: overrides.stop.clone().or_else(|| self.stop.clone()),
+            seed: overrides.seed.or(self.seed),
+            response_format: overrides.response_format.or(self.response_format),
+            system_prompt: overrides
+                .system_prompt
+                .clone()
+                .or_else(|| self.system_prompt.clone()),
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if let Some(temperature) = self.temperature
+            && !(0.0..=2.0).contains(&temperature)
+        {
+            bail!("temperatureは0.0〜2.0で指定してください: {}", temperature);
+        }
+        if let Some(top_p) = self.top_p
+            && !(0.0..=1.0).contains(&top_p)
+        {
+            bail!("top_pは0.0〜1.0で指定してください: {}", top_p);
+        }
+        if self.max_tokens == Some(0) {
+            bail!("max_tokensは1以上で指定してください");
+        }
+        Ok(())
+    }
+
+    /// モデルと合わせてLLMへのリクエスト指定にする
+    pub fn options<'a>(&'a self, model: &'a str) -> LLMOptions<'a> {
+        LLMOptions {
+            model,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.max_tokens,
+            stop: self.stop.as_deref().unwrap_or_default(),
+            seed: self.seed,
+            response_format: self.response_format,
+            system_prompt: self.system_prompt.as_deref(),
+            tools: &[],
+        }
+    }
+}
+
+// ペルソナの設定
+//
+// ```toml
+// [personas]
+// default = "assistant"
+//
+// [personas.definitions.assistant]
+// description = "汎用アシスタント"
+// system_prompt = "あなたはチームのSlackアシスタントです。日本語で簡潔に回答してください。"
+//
+// [personas.definitions.support]
+// description = "顧客サポート"
+// system_prompt = "あなたは丁寧なサポート担当です。"
+//
+// [personas.channels]
+// C0123456 = "support"
+// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersonasConfig {
+    /// チャンネルに割り当てがない場合のペルソナ
+    pub default: Option<String>,
+    pub definitions: HashMap<String, PersonaDefinition>,
+    /// チャンネルIDごとのペルソナ名
+    pub channels: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PersonaDefinition {
+    #[serde(default)]
+    pub description: String,
+    pub system_prompt: String,
+}
+
+impl PersonasConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        let assigned = self
+            .default
+            .iter()
+            .chain(self.channels.values())
+            .find(|name| !self.definitions.contains_key(*name));
+        if let Some(name) = assigned {
+            bail!(
+                "[personas] 定義されていないペルソナが割り当てられています: {}",
+                name
+            );
+        }
+        Ok(())
+    }
+}
+
+// 通常のメッセージに応答するかのポリシー
+//
+// ```toml
+// [policy]
+// admins = ["U0123456"]
+//
+// [policy.default]
+// mode = "mention_only"
+//
+// [policy.channels.C0123456]
+// mode = "keywords"
+// keywords = ["stockmind", "教えて"]
+// ```
+//
+// modeは mention_only / dms_only / joined_threads / all_messages / keywords / questions
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    pub default: ChannelPolicyConfig,
+    pub channels: HashMap<String, ChannelPolicyConfig>,
+    /// スラッシュコマンドでポリシーを変更できるユーザー(ワークスペースの管理者は常に可能)
+    pub admins: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelPolicyConfig {
+    pub mode: RespondMode,
+    pub keywords: Vec<String>,
+}
+
+impl PolicyConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        self.default.validate().context("[policy.default]")?;
+        for (channel, config) in &self.channels {
+            config
+                .validate()
+                .with_context(|| format!("[policy.channels.{}]", channel))?;
+        }
+        Ok(())
+    }
+}
+
+// 定期ダイジェストの設定
+//
+// ```toml
+// [[digests]]
+// name = "dev-daily"
+// channel = "C0123456"              # 投稿先
+// source_channels = ["C0123456"]    # 要約するチャンネル(省略時は投稿先)
+// schedule = "0 0 9 * * Mon-Fri"    # 秒 分 時 日 月 曜日
+// timezone = "Asia/Tokyo"
+// period_hours = 24                 # 前回の投稿がない場合に遡る時間
+// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DigestConfig {
+    /// 実行記録のキー。変更すると別のダイジェストとして扱う
+    pub name: String,
+    pub channel: String,
+    #[serde(default)]
+    pub source_channels: Vec<String>,
+    pub schedule: String,
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    #[serde(default = "default_period_hours")]
+    pub period_hours: u64,
+}
+
+fn default_timezone() -> String {
+    "Asia/Tokyo".to_string()
+}
+
+fn default_period_hours() -> u64 {
+    24
+}
+
+fn validate_digests(digests: &[DigestConfig]) -> anyhow::Result<()> {
+    for (i, digest) in digests.iter().enumerate() {
+        if digests[..i].iter().any(|other| other.name == digest.name) {
+            bail!("[[digests]] nameが重複しています: {}", digest.name);
+        }
+        digest
+            .validate()
+            .with_context(|| format!("[[digests]] {}", digest.name))?;
+    }
+    Ok(())
+}
+
+// 環境変数があれば解析して上書きする。解析できなければエラーに加える
+fn parse_env<T: FromStr>(key: &str, target: &mut T, errors: &mut Vec<String>) {
+    if let Ok(value) = std::env::var(key) {
+        match value.parse() {
+            Ok(parsed) => *target = parsed,
+            Err(_) => errors.push(format!("{}が不正です: {}", key, value)),
+        }
+    }
+}
+
+// true/false(1/0)の環境変数を解析して上書きする
+fn parse_flag_env(key: &str, target: &mut bool, errors: &mut Vec<String>) {
+    match std::env::var(key).as_deref() {
+        Ok("true" | "1") => *target = true,
+        Ok("false" | "0") => *target = false,
+        Ok(value) => errors.push(format!("{}はtrueかfalseで指定してください: {}", key, value)),
+        Err(_) => {}
+    }
+}
+
+impl RuntimeConfig {
+    // 設定ファイルを読み込む(検証はしない)
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("設定ファイルを読み込めません: {}", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("設定ファイルの形式が不正です: {}", path.display()))
+    }
+
+    /// 設定ファイル・環境変数・コマンドライン引数を重ねて読み込み、検証する
+    ///
+    /// 不足・不正な項目があれば、すべてを1つのエラーにまとめて返す。
+    /// 読み込んだ設定ファイルのパス(なければNone)も返す。
+    pub fn load(overrides: &ConfigOverrides) -> anyhow::Result<(Self, Option<PathBuf>)> {
+        let path = overrides
+            .config
+            .clone()
+            .or_else(|| std::env::var("STOCKMIND_CONFIG").ok().map(PathBuf::from))
+            .or_else(|| Some(PathBuf::from(DEFAULT_CONFIG_PATH)).filter(|path| path.exists()));
+        let config = Self::load_from(path.as_deref(), overrides)?;
+        Ok((config, path))
+    }
+
+    /// `path`の設定ファイル(Noneなら既定値)に環境変数とコマンドライン引数を重ねて読み込み、検証する
+    ///
+    /// `path`のファイルがなければエラーにする(既定値には戻さない)。
+    pub fn load_from(path: Option<&Path>, overrides: &ConfigOverrides) -> anyhow::Result<Self> {
+        let mut config = match path {
+            Some(path) => Self::read(path)?,
+            None => Self::default(),
+        };
+
+        let mut errors = Vec::new();
+        config.apply_env(&mut errors);
+        config.apply_overrides(overrides, &mut errors);
+        errors.extend(config.problems());
+        if !errors.is_empty() {
+            let source = path.map_or("既定値".to_string(), |path| path.display().to_string());
+            bail!(
+                "設定が不正です({}件, {}):\n{}",
+                errors.len(),
+                source,
+                errors
+                    .iter()
+                    .map(|e| format!("  - {}", e))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
+        }
+        Ok(config)
+    }
+
+    // 環境変数の指定で上書きする
+    //
+    // - `SLACK_SIGNING_SECRET` / `SLACK_BOT_TOKEN` / `SLACK_APP_TOKEN` / `SLACK_TRANSPORT`
+    // - `SOCKET_MODE_URL` / `NGROK_DOMAIN` / `LISTEN_ADDR` / `TLS_CERT_PATH` / `TLS_KEY_PATH`
+    // - `SHUTDOWN_TIMEOUT_SECS`
+    // - `API_URL` / `OPERATOR_ID` / `API_TOKEN` / `EMBEDDING_API_URL` / `EMBEDDING_MODEL`
+    // - `LLM_MAX_RETRIES` / `LLM_TIMEOUT_SECS`
+    // - `STOCKMIND_DB_PATH` / `VECTOR_INDEX_PATH` / `LOG_LEVEL`
+    // - `LLM_MODEL`: 既定のモデル
+    // - `LLM_ALLOWED_MODELS`: `--model`で指定できるモデル(カンマ区切り)
+    // - `RESPOND_TO_OTHER_BOTS`: 他のボットの発言に応答するか(true/false)
+    // - `LLM_MAX_CONCURRENCY` / `LLM_QUEUE_SIZE`
+    // - `USER_RATE_LIMIT_PER_MINUTE` / `USER_RATE_LIMIT_BURST`
+    // - `CHANNEL_RATE_LIMIT_PER_MINUTE` / `CHANNEL_RATE_LIMIT_BURST`
+    // - `EVENT_DEDUP_TTL_SECS`
+    // - `CONVERSATION_MAX_TURNS` / `CONVERSATION_MAX_TOKENS`
+    // - `RETRIEVAL_TOP_K` / `RETRIEVAL_SCOPE`(channel/workspace)
+    // - `STREAMING_REPLY`(true/false) / `STREAMING_UPDATE_INTERVAL_MS`
+    // - `SUMMARY_CHUNK_TOKENS` / `SUMMARY_MAX_MESSAGES` / `SUMMARY_DEFAULT_HOURS`
+    // - `TOOL_CALLING`(true/false) / `TOOL_MAX_ITERATIONS`
+    // - `HEALTH_SLACK_PROBE_INTERVAL_SECS` / `HEALTH_LLM_PROBE_INTERVAL_SECS` /
+    //   `HEALTH_QUEUE_SATURATION`
+    fn apply_env(&mut self, errors: &mut Vec<String>) {
+        let env = |key: &str| std::env::var(key).ok();
+
+        if let Some(value) = env("SLACK_SIGNING_SECRET") {
+            self.slack.signing_secret = Some(value.into());
+        }
+        if let Some(value) = env("SLACK_BOT_TOKEN") {
+            self.slack.bot_token = Some(value.into());
+        }
+        if let Some(value) = env("SLACK_APP_TOKEN") {
+            self.slack.app_token = Some(value.into());
+        }
+        if let Some(value) = env("SLACK_TRANSPORT") {
+            match value.parse() {
+                Ok(transport) => self.slack.transport = transport,
+                Err(e) => errors.push(format!("SLACK_TRANSPORT: {}", e)),
+            }
+        }
+        if let Some(value) = env("SOCKET_MODE_URL") {
+            self.slack.socket_mode_url = Some(value);
+        }
+
+        if let Some(value) = env("NGROK_DOMAIN") {
+            self.server.ngrok_domain = Some(value);
+        }
+        if let Some(value) = env("LISTEN_ADDR") {
+            self.server.listen = value;
+        }
+        if let Some(value) = env("TLS_CERT_PATH") {
+            self.server.tls_cert = Some(value.into());
+        }
+        if let Some(value) = env("TLS_KEY_PATH") {
+            self.server.tls_key = Some(value.into());
+        }
+        parse_env(
+            "SHUTDOWN_TIMEOUT_SECS",
+            &mut self.server.shutdown_timeout_secs,
+            errors,
+        );
+
+        if let Some(value) = env("API_URL") {
+            self.llm.api_url = Some(value);
+        }
+        if let Some(value) = env("OPERATOR_ID") {
+            self.llm.operator_id = Some(value);
+        }
+        if let Some(value) = env("API_TOKEN") {
+            self.llm.api_token = Some(value.into());
+        }
+        if let Some(value) = env("EMBEDDING_API_URL") {
+            self.llm.embedding_url = Some(value);
+        }
+        if let Some(value) = env("EMBEDDING_MODEL") {
+            self.llm.embedding_model = Some(value);
+        }
+        parse_env("LLM_MAX_RETRIES", &mut self.llm.retry.max_retries, errors);
+        parse_env("LLM_TIMEOUT_SECS", &mut self.llm.retry.timeout_secs, errors);
+
+        if let Some(value) = env("STOCKMIND_DB_PATH") {
+            self.storage.db_path = value;
+        }
+        if let Some(value) = env("VECTOR_INDEX_PATH") {
+            self.storage.vector_index_path = value;
+        }
+        if let Some(value) = env("LOG_LEVEL") {
+            self.log.level = value;
+        }
+
+        if let Some(model) = env("LLM_MODEL") {
+            self.models.default = model;
+        }
+        if let Some(models) = env("LLM_ALLOWED_MODELS") {
+            self.models.allowed = models
+                .split(',')
+                .map(|m| m.trim().to_string())
+                .filter(|m| !m.is_empty())
+                .collect();
+        }
+
+        parse_flag_env(
+            "RESPOND_TO_OTHER_BOTS",
+            &mut self.bot.respond_to_other_bots,
+            errors,
+        );
+
+        let limits = &mut self.limits;
+        parse_env("LLM_MAX_CONCURRENCY", &mut limits.max_concurrency, errors);
+        parse_env("LLM_QUEUE_SIZE", &mut limits.queue_size, errors);
+        parse_env(
+            "USER_RATE_LIMIT_PER_MINUTE",
+            &mut limits.user.per_minute,
+            errors,
+        );
+        parse_env("USER_RATE_LIMIT_BURST", &mut limits.user.burst, errors);
+        parse_env(
+            "CHANNEL_RATE_LIMIT_PER_MINUTE",
+            &mut limits.channel.per_minute,
+            errors,
+        );
+        parse_env(
+            "CHANNEL_RATE_LIMIT_BURST",
+            &mut limits.channel.burst,
+            errors,
+        );
+
+        parse_env("EVENT_DEDUP_TTL_SECS", &mut self.dedup.ttl_secs, errors);
+
+        let conversation = &mut self.conversation;
+        parse_env(
+            "CONVERSATION_MAX_TURNS",
+            &mut conversation.max_turns,
+            errors,
+        );
+        parse_env(
+            "CONVERSATION_MAX_TOKENS",
+            &mut conversation.max_tokens,
+            errors,
+        );
+
+        parse_env("RETRIEVAL_TOP_K", &mut self.retrieval.top_k, errors);
+        parse_env("RETRIEVAL_SCOPE", &mut self.retrieval.scope, errors);
+
+        parse_flag_env("STREAMING_REPLY", &mut self.streaming.enabled, errors);
+        parse_env(
+            "STREAMING_UPDATE_INTERVAL_MS",
+            &mut self.streaming.update_interval_ms,
+            errors,
+        );
+
+        let summary = &mut self.summary;
+        parse_env("SUMMARY_CHUNK_TOKENS", &mut summary.chunk_tokens, errors);
+        parse_env("SUMMARY_MAX_MESSAGES", &mut summary.max_messages, errors);
+        parse_env("SUMMARY_DEFAULT_HOURS", &mut summary.default_hours, errors);
+
+        parse_flag_env("TOOL_CALLING", &mut self.tools.enabled, errors);
+        parse_env(
+            "TOOL_MAX_ITERATIONS",
+            &mut self.tools.max_iterations,
+            errors,
+        );
+
+        let health = &mut self.health;
+        parse_env(
+            "HEALTH_SLACK_PROBE_INTERVAL_SECS",
+            &mut health.slack_probe_interval_secs,
+            errors,
+        );
+        parse_env(
+            "HEALTH_LLM_PROBE_INTERVAL_SECS",
+            &mut health.llm_probe_interval_secs,
+            errors,
+        );
+        parse_env(
+            "HEALTH_QUEUE_SATURATION",
+            &mut health.queue_saturation,
+            errors,
+        );
+    }
+
+    // コマンドライン引数の指定で上書きする
+    fn apply_overrides(&mut self, overrides: &ConfigOverrides, errors: &mut Vec<String>) {
+        if let Some(transport) = &overrides.transport {
+            match transport.parse() {
+                Ok(transport) => self.slack.transport = transport,
+                Err(e) => errors.push(format!("--transport: {}", e)),
+            }
+        }
+        if let Some(listen) = &overrides.listen {
+            self.server.listen = listen.clone();
+        }
+        if let Some(level) = &overrides.log_level {
+            self.log.level = level.clone();
+        }
+        if let Some(model) = &overrides.model {
+            self.models.default = model.clone();
+        }
+    }
+
+    // 不足・不正な項目をすべて挙げる
+    fn problems(&self) -> Vec<String> {
+        let mut errors: Vec<anyhow::Error> = Vec::new();
+        let mut check = |result: anyhow::Result<()>| {
+            if let Err(e) = result {
+                errors.push(e);
+            }
+        };
+
+        let slack = &self.slack;
+        check(required(&slack.bot_token, "SLACK_BOT_TOKEN").map(drop));
+        match slack.transport {
+            TransportKind::Socket => {
+                if slack.socket_mode_url.is_none() {
+                    check(required(&slack.app_token, "SLACK_APP_TOKEN").map(drop));
+                }
+            }
+            TransportKind::Ngrok | TransportKind::Http => {
+                check(required(&slack.signing_secret, "SLACK_SIGNING_SECRET").map(drop));
+            }
+        }
+        for (key, path) in [
+            ("event_path", &slack.event_path),
+            ("command_path", &slack.command_path),
+        ] {
+            if !path.starts_with('/') {
+                check(Err(anyhow!("[slack] {}は/で始めてください: {}", key, path)));
+            }
+        }
+        if slack.event_path == slack.command_path {
+            check(Err(anyhow!(
+                "[slack] event_pathとcommand_pathが同じです: {}",
+                slack.event_path
+            )));
+        }
+
+        let server = &self.server;
+        match slack.transport {
+            TransportKind::Ngrok => {
+                check(required(&server.ngrok_domain, "NGROK_DOMAIN").map(drop));
+            }
+            TransportKind::Http => {
+                check(server.listen_addr().map(drop));
+                if server.tls_cert.is_some() != server.tls_key.is_some() {
+                    check(Err(anyhow!(
+                        "[server] tls_certとtls_keyは両方指定してください"
+                    )));
+                }
+            }
+            // Socket Modeでも/healthと/metricsはlistenで公開する
+            TransportKind::Socket => check(server.listen_addr().map(drop)),
+        }
+
+        check(required(&self.llm.api_url, "API_URL").map(drop));
+        check(required(&self.llm.operator_id, "OPERATOR_ID").map(drop));
+        check(required(&self.llm.api_token, "API_TOKEN").map(drop));
+        check(self.log.max_level().map(drop));
+        if self.limits.max_concurrency == 0 {
+            check(Err(anyhow!(
+                "[limits] max_concurrencyは1以上にしてください"
+            )));
+        }
+        if self.conversation.max_turns == 0 {
+            check(Err(anyhow!(
+                "[conversation] max_turnsは1以上にしてください"
+            )));
+        }
+        let summary = &self.summary;
+        if summary.chunk_tokens < 500 {
+            check(Err(anyhow!(
+                "[summary] chunk_tokensは500以上にしてください: {}",
+                summary.chunk_tokens
+            )));
+        }
+        if summary.max_messages == 0 || summary.default_hours == 0 {
+            check(Err(anyhow!(
+                "[summary] max_messagesとdefault_hoursは1以上にしてください"
+            )));
+        }
+        if !(0.0..=1.0).contains(&self.health.queue_saturation) {
+            check(Err(anyhow!(
+                "[health] queue_saturationは0.0〜1.0で指定してください: {}",
+                self.health.queue_saturation
+            )));
+        }
+
+        check(self.generation.validate());
+        check(self.personas.validate());
+        check(self.policy.validate());
+        check(validate_digests(&self.digests));
+
+        errors.into_iter().map(|e| format!("{:#}", e)).collect()
+    }
+}
@@ -0,0 +1,168 @@
+//! スレッド単位の会話履歴
+//!
+//! Slackのスレッドを`channel + thread_ts`で識別し、ユーザーの発言と
+//! ボット自身の過去の返信をLLMに渡す`messages`配列へ組み立てる。
+
+use crate::identity::BotIdentity;
+use crate::llm::ChatMessage;
+use crate::model_routing::parse_model_override;
+use crate::slack_api::{SlackApi, SlackMessage};
+use serde::Deserialize;
+
+// スレッドから取得する最大件数(超える場合は新しい方を残す)
+const FETCH_LIMIT: usize = 200;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ConversationKey {
+    pub channel: String,
+    pub thread_ts: String,
+}
+
+// 履歴の上限設定
+//
+// ```toml
+// [conversation]
+// max_turns = 20
+// max_tokens = 8000
+// ```
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConversationOptions {
+    /// LLMに渡す最大メッセージ数(トリガーとなったメッセージを含む)
+    pub max_turns: usize,
+    /// LLMに渡す履歴のおおよその最大トークン数
+    pub max_tokens: usize,
+}
+
+impl Default for ConversationOptions {
+    fn default() -> Self {
+        Self {
+            max_turns: 20,
+            max_tokens: 8000,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ConversationMemory {
+    slack_api: SlackApi,
+    identity: BotIdentity,
+    options: ConversationOptions,
+}
+
+impl ConversationMemory {
+    pub fn new(slack_api: SlackApi, identity: BotIdentity, options: ConversationOptions) -> Self {
+        Self {
+            slack_api,
+            identity,
+            options,
+        }
+    }
+
+    /// `ts`のメッセージが属するスレッドを取得する
+    async fn fetch_thread(
+        &self,
+        channel: &str,
+        ts: &str,
+    ) -> anyhow::Result<(ConversationKey, Vec<SlackMessage>)> {
+        let messages = self
+            .slack_api
+            .conversations_replies(channel, ts, FETCH_LIMIT)
+            .await?;
+
+        // スレッド内の返信を指定した場合は親のthread_tsで取り直す
+        let thread_ts = messages
+            .iter()
+            .find(|m| m.ts == ts)
+            .and_then(|m| m.thread_ts.clone())
+            .unwrap_or_else(|| ts.to_string());
+        let messages = if thread_ts != ts && messages.len() <= 1 {
+            self.slack_api
+                .conversations_replies(channel, &thread_ts, FETCH_LIMIT)
+                .await?
+        } else {
+            messages
+        };
+
+        let key = ConversationKey {
+            channel: channel.to_string(),
+            thread_ts,
+        };
+        Ok((key, messages))
+    }
+
+    /// `ts`のメッセージが属するスレッドにstockmindが返信しているか
+    pub async fn bot_joined_thread(&self, channel: &str, ts: &str) -> anyhow::Result<bool> {
+        let (_, thread) = self.fetch_thread(channel, ts).await?;
+        Ok(thread.iter().any(|m| m.is_from(&self.identity)))
+    }
+
+    /// スレッド全体から`messages`配列を組み立てる
+    ///
+    /// `ts`より後のメッセージは含めない。スレッドの取得に失敗した場合でも、
+    /// トリガーとなった`text`だけは必ず含める。
+    pub async fn build_messages(
+        &self,
+        channel: &str,
+        ts: &str,
+        text: &str,
+    ) -> anyhow::Result<(ConversationKey, Vec<ChatMessage>)> {
+        let (key, thread) = self.fetch_thread(channel, ts).await?;
+        let trigger_ts = parse_ts(ts);
+
+        let mut messages: Vec<ChatMessage> = thread
+            .iter()
+            .filter(|m| parse_ts(&m.ts) <= trigger_ts)
+            .filter_map(|m| {
+                let content = self.strip_bot_mention(&m.plain_text());
+                if content.is_empty() {
+                    return None;
+                }
+                Some(if m.is_from(&self.identity) {
+                    ChatMessage::assistant(content)
+                } else {
+                    ChatMessage::user(content)
+                })
+            })
+            .collect();
+
+        if !thread.iter().any(|m| m.ts == ts) {
+            messages.push(ChatMessage::user(self.strip_bot_mention(text)));
+        }
+
+        Ok((key, self.truncate(messages)))
+    }
+
+    /// 新しいメッセージから順に、ターン数とトークン数の上限に収まる分だけ残す
+    fn truncate(&self, messages: Vec<ChatMessage>) -> Vec<ChatMessage> {
+        let mut kept = Vec::new();
+        let mut tokens = 0;
+        for message in messages.into_iter().rev() {
+            let cost = approx_tokens(&message.content);
+            if !kept.is_empty()
+                && (kept.len() >= self.options.max_turns || tokens + cost > self.options.max_tokens)
+            {
+                break;
+            }
+            tokens += cost;
+            kept.push(message);
+        }
+        kept.reverse();
+        kept
+    }
+
+    // ボットへのメンションと`--model`の指定を取り除く
+    fn strip_bot_mention(&self, text: &str) -> String {
+        let text = text.replace(&format!("<@{}>", self.identity.user_id), "");
+        parse_model_override(&text).1.trim().to_string()
+    }
+}
+
+fn parse_ts(ts: &str) -> f64 {
+    ts.parse().unwrap_or(f64::MAX)
+}
+
+/// おおよそのトークン数(日本語を考慮してUTF-8バイト数/3で見積もる)
+pub fn approx_tokens(text: &str) -> usize {
+    text.len() / 3 + 1
+}
@@ -0,0 +1,143 @@
+//! イベントの重複排除
+//!
+//! チャンネルでメンションされると、Slackは同じメッセージを`app_mention`と
+//! `message`の両方で届け、応答が遅れると同じイベントを再送する。
+//! `event_id`と`(channel, ts)`をTTL付きで記録し、1つのメッセージに
+//! 一度だけ応答するようにする。
+
+use crate::metrics;
+use crate::signature;
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::info;
+
+// 件数がこれを超えたら期限切れのキーを掃除する
+const PURGE_THRESHOLD: usize = 10_000;
+
+// 設定ファイルでの指定
+//
+// ```toml
+// [dedup]
+// ttl_secs = 600
+// ```
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DedupOptions {
+    /// 処理済みのイベントとメッセージを記録しておく秒数
+    pub ttl_secs: u64,
+}
+
+impl Default for DedupOptions {
+    fn default() -> Self {
+        Self { ttl_secs: 600 }
+    }
+}
+
+impl DedupOptions {
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+}
+
+#[derive(Clone)]
+pub struct DedupCache {
+    seen: Arc<Mutex<HashMap<String, Instant>>>,
+    ttl: Duration,
+}
+
+impl Default for DedupCache {
+    fn default() -> Self {
+        Self::new(DedupOptions::default().ttl())
+    }
+}
+
+impl DedupCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            seen: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+        }
+    }
+
+    // TTL内に同じキーを見ていなければ記録してtrueを返す
+    fn first_seen(&self, key: String) -> bool {
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        if seen.len() >= PURGE_THRESHOLD {
+            seen.retain(|_, at| now.duration_since(*at) < self.ttl);
+        }
+        match seen.get(&key) {
+            Some(at) if now.duration_since(*at) < self.ttl => false,
+            _ => {
+                seen.insert(key, now);
+                true
+            }
+        }
+    }
+
+    /// Slackのイベントを初めて受け取ったか
+    pub fn first_event(&self, event_id: &str) -> bool {
+        let first = self.first_seen(format!("event:{}", event_id));
+        if !first {
+            metrics::DEDUP_HITS.with_label_values(&["event"]).inc();
+        }
+        first
+    }
+
+    /// このメッセージにまだ応答していないか(呼び出した時点で応答済みとして記録する)
+    pub fn first_reply(&self, channel: &str, ts: &str) -> bool {
+        let first = self.first_seen(format!("reply:{}:{}", channel, ts));
+        if !first {
+            metrics::DEDUP_HITS.with_label_values(&["reply"]).inc();
+        }
+        first
+    }
+}
+
+/// イベントAPIのリクエストから`event_id`を読み、再送された重複を200で読み捨てる
+///
+/// 偽造された`event_id`で本物のイベントが捨てられないよう、
+/// `signature::verify_events`より内側に置く。
+pub async fn dedup_events(
+    State(cache): State<DedupCache>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let (parts, body) = request.into_parts();
+    let bytes = match signature::read_body(body).await {
+        Ok(bytes) => bytes,
+        Err(status) => return status.into_response(),
+    };
+
+    let event_id = serde_json::from_slice::<Value>(&bytes)
+        .ok()
+        .and_then(|payload| payload["event_id"].as_str().map(str::to_string));
+    if let Some(event_id) = event_id
+        && !cache.first_event(&event_id)
+    {
+        let retry = parts
+            .headers
+            .get("X-Slack-Retry-Num")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("-");
+        info!(
+            "重複したイベントを無視: event_id={}, retry_num={}",
+            event_id, retry
+        );
+        return StatusCode::OK.into_response();
+    }
+
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
@@ -0,0 +1,282 @@
+//! 定期ダイジェスト
+//!
+//! 設定ファイルの`[[digests]]`に従い、保存済みのメッセージから前回の投稿以降の
+//! 会話を要約してチャンネルに投稿する。実行記録をストアに残すため、再起動しても
+//! 同じ回を二重に投稿しない。投稿の途中で停止した回は、チャンネルの履歴で
+//! 投稿済みかを確かめてから再開する。
+
+use crate::config::DigestConfig;
+use crate::identity::BotIdentity;
+use crate::rate_limit::WorkQueue;
+use crate::reload::RuntimeHandle;
+use crate::slack_api::SlackApi;
+use crate::store::MessageStore;
+use crate::summarize::{self, Summarizer};
+use anyhow::Context;
+use chrono::{DateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::info;
+
+// 予定時刻を確認する間隔
+const TICK_INTERVAL: Duration = Duration::from_secs(30);
+
+// 停止中に過ぎた回をさかのぼって実行する範囲
+const CATCH_UP_SECS: i64 = 6 * 60 * 60;
+
+// 失敗した回を再試行するまでの間隔
+const RETRY_INTERVAL_SECS: i64 = 5 * 60;
+
+// 1チャンネルあたり要約に使う最大メッセージ数
+const MAX_MESSAGES_PER_CHANNEL: usize = 2000;
+
+impl DigestConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.parse_schedule()?;
+        self.parse_timezone()?;
+        if self.period_hours == 0 {
+            anyhow::bail!("period_hoursは1以上にしてください");
+        }
+        Ok(())
+    }
+
+    fn parse_schedule(&self) -> anyhow::Result<Schedule> {
+        Schedule::from_str(&self.schedule)
+            .with_context(|| format!("scheduleのcron式が不正です: {}", self.schedule))
+    }
+
+    fn parse_timezone(&self) -> anyhow::Result<Tz> {
+        self.timezone
+            .parse()
+            .map_err(|_| anyhow::anyhow!("timezoneが不正です: {}", self.timezone))
+    }
+
+    fn sources(&self) -> Vec<String> {
+        if self.source_channels.is_empty() {
+            vec![self.channel.clone()]
+        } else {
+            self.source_channels.clone()
+        }
+    }
+
+    fn title(&self) -> String {
+        format!("{} ダイジェスト", self.name)
+    }
+}
+
+pub struct DigestScheduler {
+    store: MessageStore,
+    slack_api: SlackApi,
+    summarizer: Summarizer,
+    identity: BotIdentity,
+    runtime: RuntimeHandle,
+    queue: WorkQueue,
+}
+
+impl DigestScheduler {
+    pub fn new(
+        store: MessageStore,
+        slack_api: SlackApi,
+        summarizer: Summarizer,
+        identity: BotIdentity,
+        runtime: RuntimeHandle,
+    ) -> Self {
+        Self {
+            store,
+            slack_api,
+            summarizer,
+            identity,
+            runtime,
+            queue: WorkQueue::default(),
+        }
+    }
+
+    /// 要約をLLMの呼び出しと同じ待ち行列に並ばせる
+    pub fn with_queue(mut self, queue: WorkQueue) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    /// バックグラウンドで予定時刻を監視する
+    ///
+    /// 設定の再読み込みでダイジェストが追加される場合に備え、設定がなくても監視は続ける。
+    pub fn spawn(self) {
+        info!(
+            "ダイジェストのスケジューラを開始します: {}件",
+            self.runtime.current().digests.len()
+        );
+        tokio::spawn(async move { self.run().await });
+    }
+
+    async fn run(self) {
+        // ダイジェストごとに、どの時刻まで確認したか
+        let mut checked: HashMap<String, i64> = HashMap::new();
+        // 失敗した回があるダイジェストと、次に再試行する時刻
+        let mut retry_at: HashMap<String, i64> = HashMap::new();
+        let mut interval = tokio::time::interval(TICK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let now = Utc::now().timestamp();
+            let runtime = self.runtime.current();
+            for digest in &runtime.digests {
+                if retry_at.get(&digest.name).is_some_and(|at| now < *at) {
+                    continue;
+                }
+                let since = match checked.get(&digest.name) {
+                    // 失敗し続けている回もさかのぼる範囲を過ぎたら諦める
+                    Some(since) => (*since).max(now - CATCH_UP_SECS),
+                    // 最後の回が途中で止まっていれば再開できるよう、その時刻から確認する
+                    None => match self.store.last_digest_run(&digest.name) {
+                        Ok(last) => last.map_or(0, |last| last - 1).max(now - CATCH_UP_SECS),
+                        Err(e) => {
+                            info!("ダイジェストの実行記録の取得に失敗: {}", e);
+                            continue;
+                        }
+                    },
+                };
+
+                // 失敗した回は投稿済みとして記録されないため、次の確認で同じ回から再開する
+                let mut done = now;
+                for scheduled_at in due_times(digest, since, now) {
+                    if let Err(e) = self.run_once(digest, scheduled_at).await {
+                        info!(
+                            "ダイジェストの投稿に失敗: name={}, scheduled_at={}, {}",
+                            digest.name, scheduled_at, e
+                        );
+                        done = scheduled_at - 1;
+                        break;
+                    }
+                }
+                checked.insert(digest.name.clone(), done);
+                if done < now {
+                    retry_at.insert(digest.name.clone(), now + RETRY_INTERVAL_SECS);
+                } else {
+                    retry_at.remove(&digest.name);
+                }
+            }
+        }
+    }
+
+    // 予定時刻`scheduled_at`の回を実行する
+    async fn run_once(&self, digest: &DigestConfig, scheduled_at: i64) -> anyhow::Result<()> {
+        if !self.store.claim_digest_run(&digest.name, scheduled_at)? {
+            let Some(run) = self.store.digest_run(&digest.name, scheduled_at)? else {
+                return Ok(());
+            };
+            if run.posted_ts.is_some() {
+                return Ok(());
+            }
+            // 前回の実行が途中で止まっていた。投稿だけ済んでいれば記録を補う
+            if let Some(ts) = self.find_posted(digest, run.started_at).await? {
+                info!(
+                    "投稿済みのダイジェストを記録します: name={}, ts={}",
+                    digest.name, ts
+                );
+                return self
+                    .store
+                    .finish_digest_run(&digest.name, scheduled_at, &ts);
+            }
+        }
+
+        info!(
+            "ダイジェストを作成: name={}, scheduled_at={}",
+            digest.name, scheduled_at
+        );
+        let since = self
+            .store
+            .previous_digest_run(&digest.name, scheduled_at)?
+            .unwrap_or(scheduled_at - digest.period_hours as i64 * 60 * 60);
+        let text = self.compose(digest, since, scheduled_at).await?;
+        let ts = self
+            .slack_api
+            .chat_post_message(&digest.channel, None, &text)
+            .await?;
+        self.store
+            .finish_digest_run(&digest.name, scheduled_at, &ts)
+    }
+
+    // 各チャンネルの`[since, until)`の会話を要約して1つの投稿にする
+    async fn compose(
+        &self,
+        digest: &DigestConfig,
+        since: i64,
+        until: i64,
+    ) -> anyhow::Result<String> {
+        let mut sections = vec![format!("*{}*", digest.title())];
+        for channel in digest.sources() {
+            let messages =
+                self.store
+                    .messages_between(&channel, since, until, MAX_MESSAGES_PER_CHANNEL)?;
+            let lines: Vec<String> = messages
+                .iter()
+                .filter(|m| m.sender != self.identity.user_id && !m.text.trim().is_empty())
+                .map(|m| format!("<@{}>: {}", m.sender, m.text.replace('\n', " ")))
+                .collect();
+            let title = format!("<#{}>", channel);
+            if lines.is_empty() {
+                sections.push(format!("*{}*\n期間中のメッセージはありません。", title));
+                continue;
+            }
+
+            let model = self
+                .runtime
+                .current()
+                .models
+                .resolve(None, &channel, None)
+                .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+            // 対話の応答と同じ待ち行列に並び、満杯ならこの回は失敗とする
+            let slot = self
+                .queue
+                .try_enqueue()
+                .ok_or_else(|| anyhow::anyhow!("待ち行列が満杯のため要約できません"))?;
+            let _permit = slot.start().await;
+            match self
+                .summarizer
+                .summarize_lines(&title, &lines, &model)
+                .await
+            {
+                Ok(summary) => sections.push(summary),
+                Err(e) => {
+                    info!("チャンネルの要約に失敗: channel={}, {}", channel, e);
+                    sections.push(format!("*{}*\n{}", title, summarize::failure_message(&e)));
+                }
+            }
+        }
+        Ok(sections.join("\n\n"))
+    }
+
+    // `started_at`以降にこのダイジェストを投稿していればそのtsを返す
+    async fn find_posted(
+        &self,
+        digest: &DigestConfig,
+        started_at: i64,
+    ) -> anyhow::Result<Option<String>> {
+        let title = format!("*{}*", digest.title());
+        let history = self
+            .slack_api
+            .conversations_history(&digest.channel, &started_at.to_string(), 200)
+            .await?;
+        Ok(history
+            .into_iter()
+            .find(|m| m.is_from(&self.identity) && m.text.starts_with(&title))
+            .map(|m| m.ts))
+    }
+}
+
+// `(since, now]`に入る予定時刻(Unix秒)
+fn due_times(digest: &DigestConfig, since: i64, now: i64) -> Vec<i64> {
+    let (Ok(schedule), Ok(tz)) = (digest.parse_schedule(), digest.parse_timezone()) else {
+        return Vec::new();
+    };
+    let Some(start) = Utc.timestamp_opt(since, 0).single() else {
+        return Vec::new();
+    };
+    schedule
+        .after(&start.with_timezone(&tz))
+        .map(|time: DateTime<Tz>| time.timestamp())
+        .take_while(|time| *time <= now)
+        .collect()
+}
@@ -0,0 +1,113 @@
+//! slack_rsのイベント型にないフィールドの引き渡し
+//!
+//! slack_rsの`Event`は`thread_ts`や`app_mention`の送信者、ボットのIDなどの
+//! フィールドを持たないため、受信したペイロードから読み取って`(channel, ts)`を
+//! キーに記録し、イベントハンドラから参照できるようにする。HTTPでは署名を検証した
+//! 後のミドルウェア、Socket Modeでは受信時に記録する。偽造されたリクエストで
+//! 送信者やスレッドを書き換えられないよう、検証前の内容は記録しない。
+
+use crate::signature;
+use axum::{
+    body::Body,
+    extract::State,
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+// ハンドラが参照するまで保持する時間
+const TTL: Duration = Duration::from_secs(60);
+
+// 件数がこれを超えたら期限切れのものを掃除する
+const PURGE_THRESHOLD: usize = 10_000;
+
+/// slack_rsのイベント型にないフィールド
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventDetails {
+    /// スレッド内のメッセージなら親メッセージのts
+    pub thread_ts: Option<String>,
+    /// イベントが発生したワークスペースのID
+    pub team: Option<String>,
+    /// 送信者のユーザーID
+    pub user: Option<String>,
+    /// ボットやアプリの投稿ならそのボットID
+    pub bot_id: Option<String>,
+}
+
+impl EventDetails {
+    /// イベントAPIのペイロードから読み取る
+    fn from_payload(payload: &Value) -> Self {
+        let event = &payload["event"];
+        let field = |value: &Value| value.as_str().map(str::to_string);
+        Self {
+            thread_ts: field(&event["thread_ts"]),
+            team: field(&event["team"]).or_else(|| field(&payload["team_id"])),
+            user: field(&event["user"]),
+            bot_id: field(&event["bot_id"]),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct EventDetailsCache {
+    entries: Arc<Mutex<HashMap<(String, String), (Instant, EventDetails)>>>,
+}
+
+impl EventDetailsCache {
+    /// イベントAPIのペイロード(`event_callback`)からイベントのフィールドを記録する
+    pub fn record(&self, payload: &Value) {
+        let event = &payload["event"];
+        let (Some(channel), Some(ts)) = (event["channel"].as_str(), event["ts"].as_str()) else {
+            return;
+        };
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() >= PURGE_THRESHOLD {
+            entries.retain(|_, (at, _)| now.duration_since(*at) < TTL);
+        }
+        entries.insert(
+            (channel.to_string(), ts.to_string()),
+            (now, EventDetails::from_payload(payload)),
+        );
+    }
+
+    /// 記録したフィールドを取得する。記録がなければ空のフィールドを返す
+    ///
+    /// メンションは`app_mention`と`message`の両方で届くため、取得後も記録は残す。
+    pub fn get(&self, channel: &str, ts: &str) -> EventDetails {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries
+            .get(&(channel.to_string(), ts.to_string()))
+            .filter(|(at, _)| at.elapsed() < TTL)
+            .map(|(_, details)| details.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// イベントAPIのリクエストボディからフィールドを記録してからハンドラに渡す
+///
+/// `signature::verify_events`より内側に置き、署名を検証したリクエストだけを記録する。
+pub async fn record_events(
+    State(cache): State<EventDetailsCache>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let (parts, body) = request.into_parts();
+    let bytes = match signature::read_body(body).await {
+        Ok(bytes) => bytes,
+        Err(status) => return status.into_response(),
+    };
+
+    if let Ok(payload) = serde_json::from_slice::<Value>(&bytes) {
+        cache.record(&payload);
+    }
+
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
@@ -0,0 +1,258 @@
+//! ヘルスチェック
+//!
+//! `/health`は従来どおり`OK`とだけ返し、`/health/live`はプロセスが応答できるかだけを
+//! JSONで返す。`/health/ready`はSlack(`auth.test`)、LLMゲートウェイ、メッセージストア、
+//! 待ち行列の状態を確認し、コンポーネントごとの結果をJSONで返す。いずれかが失敗して
+//! いれば503を返す。
+//! 短い間隔で問い合わせてもSlackの頻度制限やLLMのトークンを消費しすぎないよう、
+//! SlackとLLMゲートウェイの確認結果は一定時間使い回す。
+//! 失敗の詳細はログにだけ出し、応答には含めない。
+
+use crate::llm::LLMClient;
+use crate::rate_limit::WorkQueue;
+use crate::reload::RuntimeHandle;
+use crate::slack_api::SlackApi;
+use crate::store::MessageStore;
+use axum::Router;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Json};
+use axum::routing::get;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::info;
+
+// 1つのコンポーネントの確認にかける時間の上限
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 設定ファイルでの指定
+//
+// ```toml
+// [health]
+// slack_probe_interval_secs = 60
+// llm_probe_interval_secs = 300
+// queue_saturation = 0.9
+// ```
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthOptions {
+    /// Slackの確認結果を使い回す秒数
+    pub slack_probe_interval_secs: u64,
+    /// LLMゲートウェイの確認結果を使い回す秒数
+    pub llm_probe_interval_secs: u64,
+    /// 待ち行列がこの割合以上埋まっていたら準備できていないとみなす
+    pub queue_saturation: f64,
+}
+
+impl Default for HealthOptions {
+    fn default() -> Self {
+        Self {
+            slack_probe_interval_secs: 60,
+            llm_probe_interval_secs: 300,
+            queue_saturation: 0.9,
+        }
+    }
+}
+
+// 1つのコンポーネントの確認結果
+#[derive(Debug, Clone)]
+struct Check {
+    ok: bool,
+    detail: Value,
+}
+
+impl Check {
+    fn to_json(&self) -> Value {
+        let mut value = json!({ "status": status(self.ok) });
+        if let (Value::Object(target), Value::Object(detail)) = (&mut value, &self.detail) {
+            target.extend(detail.clone());
+        }
+        value
+    }
+}
+
+// 確認結果を一定時間使い回す。確認中はロックを保持し、同時に1回だけ問い合わせる
+struct CachedProbe {
+    interval: Duration,
+    last: Mutex<Option<(Instant, Check)>>,
+}
+
+impl CachedProbe {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: Mutex::new(None),
+        }
+    }
+
+    async fn get<F>(&self, probe: impl FnOnce() -> F) -> Check
+    where
+        F: Future<Output = Check>,
+    {
+        let mut last = self.last.lock().await;
+        if let Some((checked_at, check)) = last.as_ref()
+            && checked_at.elapsed() < self.interval
+        {
+            return check.clone();
+        }
+
+        let mut check = probe().await;
+        if let Value::Object(detail) = &mut check.detail {
+            detail.insert(
+                "cached_for_secs".to_string(),
+                json!(self.interval.as_secs()),
+            );
+        }
+        *last = Some((Instant::now(), check.clone()));
+        check
+    }
+}
+
+pub struct HealthState {
+    slack_api: SlackApi,
+    llm_client: LLMClient,
+    store: MessageStore,
+    queue: WorkQueue,
+    runtime: RuntimeHandle,
+    options: HealthOptions,
+    slack_probe: CachedProbe,
+    llm_probe: CachedProbe,
+}
+
+impl HealthState {
+    pub fn new(
+        slack_api: SlackApi,
+        llm_client: LLMClient,
+        store: MessageStore,
+        queue: WorkQueue,
+        runtime: RuntimeHandle,
+        options: HealthOptions,
+    ) -> Self {
+        Self {
+            slack_api,
+            llm_client,
+            store,
+            queue,
+            runtime,
+            options,
+            slack_probe: CachedProbe::new(Duration::from_secs(options.slack_probe_interval_secs)),
+            llm_probe: CachedProbe::new(Duration::from_secs(options.llm_probe_interval_secs)),
+        }
+    }
+
+    async fn slack(&self) -> Check {
+        self.slack_probe
+            .get(|| {
+                timed("slack", async move {
+                    self.slack_api.auth_test().await.map(|_| json!({}))
+                })
+            })
+            .await
+    }
+
+    async fn llm(&self) -> Check {
+        self.llm_probe
+            .get(|| async move {
+                let model = self.runtime.current().models.default_model().to_string();
+                timed("llm", async {
+                    self.llm_client
+                        .ping(&model)
+                        .await
+                        .map(|()| json!({ "model": model }))
+                        .map_err(|e| anyhow::anyhow!("{} ({})", e, e.category()))
+                })
+                .await
+            })
+            .await
+    }
+
+    async fn storage(&self) -> Check {
+        timed("storage", async { self.store.check().map(|()| json!({})) }).await
+    }
+
+    fn queue(&self) -> Check {
+        let pending = self.queue.pending();
+        let capacity = self.queue.capacity();
+        let ratio = pending as f64 / capacity.max(1) as f64;
+        Check {
+            ok: ratio < self.options.queue_saturation,
+            detail: json!({ "pending": pending, "capacity": capacity }),
+        }
+    }
+}
+
+fn status(ok: bool) -> &'static str {
+    if ok { "ok" } else { "fail" }
+}
+
+// 確認を時間制限付きで実行し、所要時間を結果にまとめる。エラーの内容はログにだけ出す
+async fn timed(name: &str, check: impl Future<Output = anyhow::Result<Value>>) -> Check {
+    let started = Instant::now();
+    let result = tokio::time::timeout(CHECK_TIMEOUT, check).await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    let (ok, mut detail) = match result {
+        Ok(Ok(detail)) => (true, detail),
+        Ok(Err(e)) => {
+            info!("準備状態の確認に失敗: component={}, {:#}", name, e);
+            (false, json!({}))
+        }
+        Err(_) => {
+            info!(
+                "準備状態の確認がタイムアウト: component={}, timeout={:?}",
+                name, CHECK_TIMEOUT
+            );
+            (false, json!({}))
+        }
+    };
+    if let Value::Object(detail) = &mut detail {
+        detail.insert("latency_ms".to_string(), json!(latency_ms));
+    }
+    Check { ok, detail }
+}
+
+pub fn router(state: HealthState) -> Router {
+    Router::new()
+        // 互換性のため従来どおりプレーンテキストで返す
+        .route("/health", get(|| async { "OK" }))
+        .route("/health/live", get(live))
+        .route("/health/ready", get(ready))
+        .with_state(Arc::new(state))
+}
+
+async fn live() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+async fn ready(State(state): State<Arc<HealthState>>) -> impl IntoResponse {
+    let (slack, llm, storage) = tokio::join!(state.slack(), state.llm(), state.storage());
+    let queue = state.queue();
+
+    let checks = [
+        ("slack", slack),
+        ("llm", llm),
+        ("storage", storage),
+        ("queue", queue),
+    ];
+    let ready = checks.iter().all(|(_, check)| check.ok);
+    let components: serde_json::Map<String, Value> = checks
+        .iter()
+        .map(|(name, check)| (name.to_string(), check.to_json()))
+        .collect();
+    let code = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        code,
+        Json(json!({
+            "status": status(ready),
+            "components": components,
+        })),
+    )
+}
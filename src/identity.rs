@@ -0,0 +1,58 @@
+//! stockmind自身の識別情報
+//!
+//! 起動時に`auth.test`で取得したユーザーID・ボットIDを使い、自分自身や
+//! 他のボットの発言を見分ける。
+
+#[derive(Debug, Clone)]
+pub struct BotIdentity {
+    /// ボットユーザーのID(U...)
+    pub user_id: String,
+    /// ボットのID(B...)
+    pub bot_id: Option<String>,
+    pub team_id: Option<String>,
+}
+
+// メッセージの送信者の種類
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SenderKind {
+    /// stockmind自身
+    Own,
+    /// stockmind以外のボットやアプリ(ボットID)
+    OtherBot(String),
+    /// 人間のユーザー
+    User(String),
+}
+
+// 送信者を特定できないメッセージ(システムメッセージなど)の送信者
+const UNKNOWN_SENDER: &str = "unknown";
+
+impl BotIdentity {
+    /// イベントの`user`と`bot_id`から送信者を見分ける
+    pub fn classify(&self, user: Option<&str>, bot_id: Option<&str>) -> SenderKind {
+        if self.is_own(user, bot_id) {
+            return SenderKind::Own;
+        }
+        match (bot_id, user) {
+            (Some(bot_id), _) => SenderKind::OtherBot(bot_id.to_string()),
+            (None, Some(user)) => SenderKind::User(user.to_string()),
+            (None, None) => SenderKind::OtherBot(UNKNOWN_SENDER.to_string()),
+        }
+    }
+
+    /// conversations.*で取得したメッセージがstockmind自身のものか
+    pub fn is_own(&self, user: Option<&str>, bot_id: Option<&str>) -> bool {
+        user == Some(self.user_id.as_str())
+            || (bot_id.is_some() && bot_id == self.bot_id.as_deref())
+    }
+}
+
+impl SenderKind {
+    /// ストアに保存する送信者。stockmind自身はユーザーID、他のボットはボットIDで記録する
+    pub fn stored_sender(&self, identity: &BotIdentity) -> String {
+        match self {
+            Self::Own => identity.user_id.clone(),
+            Self::OtherBot(bot_id) => bot_id.clone(),
+            Self::User(id) => id.clone(),
+        }
+    }
+}
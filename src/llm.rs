@@ -0,0 +1,808 @@
+//! OpenAI互換のLLMゲートウェイ(`API_URL`)のクライアント
+//!
+//! chat completionsとembeddingsを`x-operator-id`付きで呼び出す。
+//! 一時的な失敗(通信エラー、タイムアウト、429、5xx)は指数バックオフで再試行する。
+
+use crate::metrics;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::fmt;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::info;
+
+// 設定でモデルが指定されていない場合のモデル
+pub const DEFAULT_MODEL: &str = "google_ai:gemini-2.0-flash-exp";
+
+// LLMクライアント構造体
+#[derive(Clone)]
+pub struct LLMClient {
+    http: reqwest::Client,
+    api_url: String,
+    embedding_url: String,
+    operator_id: String,
+    api_token: String,
+    retry: RetryPolicy,
+}
+
+// モデルと生成パラメータの指定
+//
+// `None`の項目はリクエストに含めず、ゲートウェイ側の既定値に任せる。
+#[derive(Clone, Default)]
+pub struct LLMOptions<'a> {
+    pub model: &'a str,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub stop: &'a [String],
+    pub seed: Option<i64>,
+    pub response_format: Option<ResponseFormat>,
+    /// 会話の先頭に置くsystemメッセージ
+    pub system_prompt: Option<&'a str>,
+    /// モデルが呼び出せるツールの定義(OpenAI形式の`tools`)
+    pub tools: &'a [Value],
+}
+
+impl LLMOptions<'_> {
+    // chat completionsのリクエストボディを組み立てる
+    fn request_body(&self, messages: &[ChatMessage], stream: bool) -> Value {
+        let model = if self.model.is_empty() {
+            DEFAULT_MODEL
+        } else {
+            self.model
+        };
+
+        let mut all_messages = Vec::with_capacity(messages.len() + 1);
+        if let Some(system_prompt) = self.system_prompt {
+            all_messages.push(ChatMessage::system(system_prompt));
+        }
+        all_messages.extend_from_slice(messages);
+
+        let mut body = json!({
+            "model": model,
+            "messages": all_messages,
+        });
+        if let Some(temperature) = self.temperature {
+            body["temperature"] = json!(temperature);
+        }
+        if let Some(top_p) = self.top_p {
+            body["top_p"] = json!(top_p);
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            body["max_tokens"] = json!(max_tokens);
+        }
+        if !self.stop.is_empty() {
+            body["stop"] = json!(self.stop);
+        }
+        if let Some(seed) = self.seed {
+            body["seed"] = json!(seed);
+        }
+        if let Some(response_format) = self.response_format {
+            body["response_format"] = json!({ "type": response_format.as_str() });
+        }
+        if !self.tools.is_empty() {
+            body["tools"] = json!(self.tools);
+        }
+        if stream {
+            body["stream"] = json!(true);
+        }
+        body
+    }
+}
+
+// 応答の形式(`json_object`でJSONモード)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+}
+
+impl ResponseFormat {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::JsonObject => "json_object",
+        }
+    }
+}
+
+// chat completionsに渡す1件のメッセージ
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatMessage {
+    pub role: &'static str,
+    pub content: String,
+    /// assistantが要求したツール呼び出し
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// toolメッセージが応答するツール呼び出しのID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    fn new(role: &'static str, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+
+    pub fn system(content: impl Into<String>) -> Self {
+        Self::new("system", content)
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new("user", content)
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self::new("assistant", content)
+    }
+
+    /// ツール呼び出しを要求したassistantの発言
+    pub fn assistant_tool_calls(content: impl Into<String>, tool_calls: Vec<ToolCall>) -> Self {
+        Self {
+            tool_calls,
+            ..Self::new("assistant", content)
+        }
+    }
+
+    /// ツールの実行結果
+    pub fn tool(tool_call_id: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id.into()),
+            ..Self::new("tool", content)
+        }
+    }
+}
+
+// モデルが要求したツール呼び出し
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type", default = "function_type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON文字列の引数
+    #[serde(default)]
+    pub arguments: String,
+}
+
+fn function_type() -> String {
+    "function".to_string()
+}
+
+// chat completionsの結果
+#[derive(Debug)]
+pub enum Completion {
+    /// 最終的な応答
+    Message(String),
+    /// ツールの実行を求められた。途中の発言があれば`content`に入る
+    ToolCalls {
+        content: String,
+        tool_calls: Vec<ToolCall>,
+    },
+}
+
+// LLM API呼び出しのエラー
+#[derive(Debug)]
+pub enum LLMError {
+    /// 接続できない、通信が途中で切れたなど
+    Network(reqwest::Error),
+    /// 応答が時間内に返らなかった
+    Timeout,
+    /// 401/403: 認証情報が誤っているか権限がない
+    Auth { status: u16, body: String },
+    /// 429: レート制限
+    RateLimited { retry_after: Option<Duration> },
+    /// 401/403/429以外の4xx: リクエスト内容の問題
+    InvalidRequest { status: u16, body: String },
+    /// 5xx: ゲートウェイまたはモデル側の障害
+    Server { status: u16, body: String },
+    /// 応答の形式が想定と異なる
+    MalformedResponse(String),
+    /// コンテンツフィルタにより生成が打ち切られた
+    ContentFiltered,
+}
+
+impl fmt::Display for LLMError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Network(e) => write!(f, "通信エラー: {}", e),
+            Self::Timeout => write!(f, "タイムアウトしました"),
+            Self::Auth { status, body } => write!(f, "認証エラー ({}): {}", status, body),
+            Self::RateLimited { retry_after } => {
+                write!(f, "レート制限されました (retry_after={:?})", retry_after)
+            }
+            Self::InvalidRequest { status, body } => {
+                write!(f, "リクエストエラー ({}): {}", status, body)
+            }
+            Self::Server { status, body } => write!(f, "サーバーエラー ({}): {}", status, body),
+            Self::MalformedResponse(detail) => write!(f, "不正な応答: {}", detail),
+            Self::ContentFiltered => {
+                write!(f, "コンテンツフィルタにより応答が生成されませんでした")
+            }
+        }
+    }
+}
+
+impl std::error::Error for LLMError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Network(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for LLMError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            Self::Timeout
+        } else {
+            Self::Network(e)
+        }
+    }
+}
+
+impl LLMError {
+    /// 再試行で解消する可能性があるか
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::Network(_) | Self::Timeout | Self::RateLimited { .. } | Self::Server { .. }
+        )
+    }
+
+    /// メトリクスのラベルに使うエラーの種類
+    pub fn category(&self) -> &'static str {
+        match self {
+            Self::Network(_) => "network",
+            Self::Timeout => "timeout",
+            Self::Auth { .. } => "auth",
+            Self::RateLimited { .. } => "rate_limited",
+            Self::InvalidRequest { .. } => "invalid_request",
+            Self::Server { .. } => "server",
+            Self::MalformedResponse(_) => "malformed_response",
+            Self::ContentFiltered => "content_filtered",
+        }
+    }
+
+    /// Slackでユーザーに返すメッセージ
+    pub fn user_message(&self) -> &'static str {
+        match self {
+            Self::Network(_) | Self::Timeout => {
+                "申し訳ありません。LLMサービスに接続できませんでした。しばらくしてからもう一度お試しください。"
+            }
+            Self::Auth { .. } => {
+                "申し訳ありません。LLMサービスの認証に失敗しました。管理者に設定の確認を依頼してください。"
+            }
+            Self::RateLimited { .. } => {
+                "申し訳ありません。LLMサービスが混み合っています。少し時間をおいてから再度お試しください。"
+            }
+            Self::InvalidRequest { .. } => {
+                "申し訳ありません。リクエストが受け付けられませんでした。スレッドを新しくするか、質問を短くして再度お試しください。"
+            }
+            Self::Server { .. } => {
+                "申し訳ありません。LLMサービスで障害が発生しています。しばらくしてからもう一度お試しください。"
+            }
+            Self::MalformedResponse(_) => {
+                "申し訳ありません。LLMサービスから想定外の応答が返されました。"
+            }
+            Self::ContentFiltered => {
+                "申し訳ありません。コンテンツポリシーにより、この内容にはお答えできません。"
+            }
+        }
+    }
+}
+
+// 再試行とタイムアウトの設定
+//
+// ```toml
+// [llm.retry]
+// max_retries = 3
+// base_delay_ms = 500
+// max_delay_secs = 30
+// timeout_secs = 120
+// ```
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// 初回を除く最大再試行回数
+    pub max_retries: u32,
+    /// 1回目の再試行までの待ち時間(以降は倍々に増やす)
+    pub base_delay_ms: u64,
+    /// 待ち時間の上限。Retry-Afterがこれを超える場合は再試行しない
+    pub max_delay_secs: u64,
+    /// 1リクエストのタイムアウト(ストリーミングではチャンク間の待ち時間)
+    pub timeout_secs: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay_ms: 500,
+            max_delay_secs: 30,
+            timeout_secs: 120,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn base_delay(&self) -> Duration {
+        Duration::from_millis(self.base_delay_ms)
+    }
+
+    pub fn max_delay(&self) -> Duration {
+        Duration::from_secs(self.max_delay_secs)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    // attempt回目(0始まり)の失敗後に待つ時間。Retry-Afterが上限を超える場合はNone
+    fn delay(&self, attempt: u32, error: &LLMError) -> Option<Duration> {
+        let backoff = self
+            .base_delay()
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay());
+        match error {
+            LLMError::RateLimited {
+                retry_after: Some(retry_after),
+            } if *retry_after > self.max_delay() => None,
+            LLMError::RateLimited {
+                retry_after: Some(retry_after),
+            } => Some((*retry_after).max(backoff)),
+            _ => Some(backoff),
+        }
+    }
+}
+
+impl LLMClient {
+    pub fn new(api_url: String, operator_id: String, api_token: String) -> Self {
+        let embedding_url = embedding_url_from(&api_url);
+        Self {
+            http: reqwest::Client::new(),
+            api_url,
+            embedding_url,
+            operator_id,
+            api_token,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    // embeddingsのエンドポイントを明示的に指定
+    pub fn with_embedding_url(mut self, embedding_url: String) -> Self {
+        self.embedding_url = embedding_url;
+        self
+    }
+
+    // 再試行とタイムアウトの設定を変更
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    // ゲートウェイにPOSTし、成功ステータスの応答を返す
+    //
+    // 再試行可能なエラーは`RetryPolicy`に従って再送する。`stream`の場合は
+    // 応答全体ではなくヘッダー受信までをタイムアウトの対象にする。
+    async fn send(
+        &self,
+        url: &str,
+        accept: &str,
+        body: &Value,
+        stream: bool,
+    ) -> Result<reqwest::Response, LLMError> {
+        let mut attempt = 0;
+        loop {
+            let mut request = self
+                .http
+                .post(url)
+                .header("Accept", accept)
+                .header("x-operator-id", &self.operator_id)
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", self.api_token))
+                .json(body);
+            if !stream {
+                request = request.timeout(self.retry.timeout());
+            }
+
+            let result = match tokio::time::timeout(self.retry.timeout(), request.send()).await {
+                Ok(Ok(response)) => check_status(response).await,
+                Ok(Err(e)) => Err(LLMError::from(e)),
+                Err(_) => Err(LLMError::Timeout),
+            };
+
+            match result {
+                Ok(response) => return Ok(response),
+                Err(e) if e.is_retryable() && attempt < self.retry.max_retries => {
+                    let Some(delay) = self.retry.delay(attempt, &e) else {
+                        info!(
+                            "Retry-Afterが待ち時間の上限({:?})を超えるため再試行しません: {}",
+                            self.retry.max_delay(),
+                            e
+                        );
+                        return Err(e);
+                    };
+                    info!(
+                        "LLM APIの呼び出しに失敗したため再試行します ({}/{}, {:?}後): {}",
+                        attempt + 1,
+                        self.retry.max_retries,
+                        delay,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // 疎通確認のため1トークンだけ生成させる
+    //
+    // ヘルスチェック用。失敗をすぐ報告できるよう再試行せず、実際の利用と
+    // 区別できなくならないようLLMのメトリクスにも記録しない。
+    pub async fn ping(&self, model: &str) -> Result<(), LLMError> {
+        let options = LLMOptions {
+            model,
+            max_tokens: Some(1),
+            ..Default::default()
+        };
+        let request_body = options.request_body(&[ChatMessage::user("ping")], false);
+        let client = Self {
+            retry: RetryPolicy {
+                max_retries: 0,
+                ..self.retry
+            },
+            ..self.clone()
+        };
+        let response = client
+            .send(&self.api_url, "application/json", &request_body, false)
+            .await?;
+        read_json(response).await.map(|_| ())
+    }
+
+    // 会話履歴を含む複数メッセージで応答を取得
+    pub async fn get_chat_response(
+        &self,
+        messages: &[ChatMessage],
+        options: Option<LLMOptions<'_>>,
+    ) -> Result<String, LLMError> {
+        match self.get_chat_completion(messages, options).await? {
+            Completion::Message(content) => Ok(content),
+            Completion::ToolCalls { .. } => Err(LLMError::MalformedResponse(
+                "ツールを渡していないのにtool_callsが返されました".to_string(),
+            )),
+        }
+    }
+
+    // ツール呼び出しを含む応答を取得
+    pub async fn get_chat_completion(
+        &self,
+        messages: &[ChatMessage],
+        options: Option<LLMOptions<'_>>,
+    ) -> Result<Completion, LLMError> {
+        let request_body = options.unwrap_or_default().request_body(messages, false);
+        let model = model_of(&request_body);
+        observe(&model, async {
+            let response = self
+                .send(&self.api_url, "application/json", &request_body, false)
+                .await?;
+            let response_json = read_json(response).await?;
+            metrics::record_tokens(&model, &response_json["usage"]);
+
+            let choice = &response_json["choices"][0];
+            if choice["finish_reason"].as_str() == Some("content_filter") {
+                return Err(LLMError::ContentFiltered);
+            }
+            let message = &choice["message"];
+            if let Some(tool_calls) = message.get("tool_calls").filter(|v| !v.is_null()) {
+                let tool_calls: Vec<ToolCall> = serde_json::from_value(tool_calls.clone())
+                    .map_err(|e| {
+                        LLMError::MalformedResponse(format!("tool_callsを解析できません: {}", e))
+                    })?;
+                if !tool_calls.is_empty() {
+                    return Ok(Completion::ToolCalls {
+                        content: message["content"].as_str().unwrap_or_default().to_string(),
+                        tool_calls,
+                    });
+                }
+            }
+            let content = message["content"]
+                .as_str()
+                .ok_or_else(|| {
+                    LLMError::MalformedResponse(
+                        "choices[0].message.contentがありません".to_string(),
+                    )
+                })?
+                .to_string();
+
+            Ok(Completion::Message(content))
+        })
+        .await
+    }
+
+    // SSEで応答をストリーミング取得し、届いたテキスト片を`tx`に送る
+    //
+    // 戻り値は最終的な応答全体。`tx`は関数の終了時に閉じられる。
+    pub async fn stream_chat_response(
+        &self,
+        messages: &[ChatMessage],
+        options: Option<LLMOptions<'_>>,
+        tx: mpsc::UnboundedSender<String>,
+    ) -> Result<String, LLMError> {
+        let request_body = options.unwrap_or_default().request_body(messages, true);
+        let model = model_of(&request_body);
+        observe(&model, async {
+            let mut response = self
+                .send(&self.api_url, "text/event-stream", &request_body, true)
+                .await?;
+
+            // チャンクの境界がUTF-8の途中になり得るため、行単位でデコードする
+            let mut buffer: Vec<u8> = Vec::new();
+            let mut content = String::new();
+            let mut filtered = false;
+            'stream: loop {
+                let chunk = tokio::time::timeout(self.retry.timeout(), response.chunk())
+                    .await
+                    .map_err(|_| LLMError::Timeout)??;
+                let Some(chunk) = chunk else {
+                    break;
+                };
+                buffer.extend_from_slice(&chunk);
+                while let Some(newline) = buffer.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=newline).collect();
+                    let line = String::from_utf8_lossy(&line);
+                    let Some(data) = line.trim().strip_prefix("data:") else {
+                        continue;
+                    };
+                    let data = data.trim();
+                    if data == "[DONE]" {
+                        break 'stream;
+                    }
+                    let Ok(event) = serde_json::from_str::<Value>(data) else {
+                        continue;
+                    };
+                    // 最後のチャンクにusageを付けるゲートウェイがある
+                    if !event["usage"].is_null() {
+                        metrics::record_tokens(&model, &event["usage"]);
+                    }
+                    let choice = &event["choices"][0];
+                    if choice["finish_reason"].as_str() == Some("content_filter") {
+                        filtered = true;
+                    }
+                    if let Some(delta) = choice["delta"]["content"].as_str()
+                        && !delta.is_empty()
+                    {
+                        content.push_str(delta);
+                        // 受信側が終了していても応答全体は返す
+                        let _ = tx.send(delta.to_string());
+                    }
+                }
+            }
+
+            if content.is_empty() {
+                return Err(if filtered {
+                    LLMError::ContentFiltered
+                } else {
+                    LLMError::MalformedResponse("ストリームに応答が含まれていません".to_string())
+                });
+            }
+            Ok(content)
+        })
+        .await
+    }
+
+    // テキストの埋め込みベクトルを取得(入力と同じ順序で返す)
+    pub async fn get_embeddings(
+        &self,
+        inputs: &[String],
+        model: &str,
+    ) -> Result<Vec<Vec<f32>>, LLMError> {
+        let request_body = json!({
+            "model": model,
+            "input": inputs,
+        });
+        observe(model, async {
+            let response = self
+                .send(
+                    &self.embedding_url,
+                    "application/json",
+                    &request_body,
+                    false,
+                )
+                .await?;
+            let response_json = read_json(response).await?;
+            metrics::record_tokens(model, &response_json["usage"]);
+
+            let malformed = |detail: &str| LLMError::MalformedResponse(detail.to_string());
+            let mut data = response_json["data"]
+                .as_array()
+                .ok_or_else(|| malformed("embeddingsの応答にdataがありません"))?
+                .clone();
+            data.sort_by_key(|item| item["index"].as_u64().unwrap_or_default());
+
+            let embeddings = data
+                .iter()
+                .map(|item| {
+                    item["embedding"]
+                        .as_array()
+                        .map(|values| {
+                            values
+                                .iter()
+                                .filter_map(|v| v.as_f64().map(|v| v as f32))
+                                .collect::<Vec<f32>>()
+                        })
+                        .ok_or_else(|| malformed("embeddingsの応答にembeddingがありません"))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if embeddings.len() != inputs.len() {
+                return Err(malformed("embeddingsの応答件数が入力と一致しません"));
+            }
+
+            Ok(embeddings)
+        })
+        .await
+    }
+}
+
+// 呼び出しの所要時間と結果をメトリクスに記録する
+async fn observe<T>(
+    model: &str,
+    call: impl Future<Output = Result<T, LLMError>>,
+) -> Result<T, LLMError> {
+    let started = Instant::now();
+    let result = call.await;
+    metrics::record_llm_call(
+        model,
+        started.elapsed(),
+        result.as_ref().err().map(LLMError::category),
+    );
+    result
+}
+
+fn model_of(request_body: &Value) -> String {
+    request_body["model"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string()
+}
+
+// ステータスコードをエラーの種類に振り分ける
+async fn check_status(response: reqwest::Response) -> Result<reqwest::Response, LLMError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let retry_after = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_retry_after);
+    let body = response.text().await.unwrap_or_default();
+    Err(status_error(status.as_u16(), retry_after, body))
+}
+
+// Retry-After(秒数)を解析する。日時形式には対応しない
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse::<u64>().ok().map(Duration::from_secs)
+}
+
+// 成功以外のステータスコードをエラーの種類に振り分ける
+fn status_error(code: u16, retry_after: Option<Duration>, body: String) -> LLMError {
+    match code {
+        401 | 403 => LLMError::Auth { status: code, body },
+        429 => LLMError::RateLimited { retry_after },
+        400..=499 => LLMError::InvalidRequest { status: code, body },
+        _ => LLMError::Server { status: code, body },
+    }
+}
+
+async fn read_json(response: reqwest::Response) -> Result<Value, LLMError> {
+    let text = response.text().await?;
+    serde_json::from_str(&text).map_err(|e| LLMError::MalformedResponse(e.to_string()))
+}
+
+// chat completionsのURLから同じゲートウェイのembeddingsのURLを導出
+fn embedding_url_from(api_url: &str) -> String {
+    match api_url.strip_suffix("/chat/completions") {
+        Some(base) => format!("{}/embeddings", base),
+        None => format!("{}/embeddings", api_url.trim_end_matches('/')),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate_limited(secs: u64) -> LLMError {
+        LLMError::RateLimited {
+            retry_after: Some(Duration::from_secs(secs)),
+        }
+    }
+
+    #[test]
+    fn maps_status_codes_to_error_kinds() {
+        let kind = |code| status_error(code, None, String::new()).category();
+        assert_eq!(kind(401), "auth");
+        assert_eq!(kind(403), "auth");
+        assert_eq!(kind(429), "rate_limited");
+        assert_eq!(kind(400), "invalid_request");
+        assert_eq!(kind(404), "invalid_request");
+        assert_eq!(kind(500), "server");
+        assert_eq!(kind(503), "server");
+
+        assert!(status_error(429, None, String::new()).is_retryable());
+        assert!(status_error(502, None, String::new()).is_retryable());
+        assert!(!status_error(401, None, String::new()).is_retryable());
+        assert!(!status_error(422, None, String::new()).is_retryable());
+    }
+
+    #[test]
+    fn parses_retry_after_seconds() {
+        assert_eq!(parse_retry_after("7"), Some(Duration::from_secs(7)));
+        assert_eq!(parse_retry_after(" 120 "), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+        assert!(matches!(
+            status_error(429, parse_retry_after("7"), String::new()),
+            LLMError::RateLimited { retry_after: Some(d) } if d == Duration::from_secs(7)
+        ));
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_cap() {
+        let policy = RetryPolicy::default();
+        let delay = |attempt| policy.delay(attempt, &LLMError::Timeout);
+        assert_eq!(delay(0), Some(Duration::from_millis(500)));
+        assert_eq!(delay(1), Some(Duration::from_secs(1)));
+        assert_eq!(delay(2), Some(Duration::from_secs(2)));
+        assert_eq!(delay(10), Some(policy.max_delay()));
+        assert_eq!(delay(u32::MAX), Some(policy.max_delay()));
+    }
+
+    #[test]
+    fn honors_retry_after_within_the_cap() {
+        let policy = RetryPolicy::default();
+        assert_eq!(
+            policy.delay(0, &rate_limited(5)),
+            Some(Duration::from_secs(5))
+        );
+        // バックオフより短いRetry-Afterではバックオフを使う
+        assert_eq!(
+            policy.delay(3, &rate_limited(1)),
+            Some(Duration::from_secs(4))
+        );
+        assert_eq!(
+            policy.delay(0, &LLMError::RateLimited { retry_after: None }),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            policy.delay(0, &rate_limited(policy.max_delay_secs)),
+            Some(policy.max_delay())
+        );
+    }
+
+    #[test]
+    fn gives_up_when_retry_after_exceeds_the_cap() {
+        let policy = RetryPolicy::default();
+        assert_eq!(
+            policy.delay(0, &rate_limited(policy.max_delay_secs + 1)),
+            None
+        );
+        assert_eq!(policy.delay(0, &rate_limited(3600)), None);
+    }
+}
@@ -1,95 +1,147 @@
-use axum::{Router, routing::get};
-use ngrok::prelude::*;
-use reqwest;
-use serde_json::{Value, json};
+mod cli;
+mod config;
+mod conversation;
+mod dedup;
+mod digest;
+mod event_details;
+mod health;
+mod identity;
+mod llm;
+mod metrics;
+mod model_routing;
+mod persona;
+mod pipeline;
+mod policy;
+mod rate_limit;
+mod reload;
+mod retrieval;
+mod shutdown;
+mod signature;
+mod slack_api;
+mod slash_command;
+mod socket_mode;
+mod store;
+mod streaming;
+mod summarize;
+mod tools;
+mod transport;
+mod vector_index;
+
+use axum::{Router, middleware, routing::get};
+use clap::Parser;
+use cli::{Cli, Command, ConfigCommand};
+use config::{
+    BotConfig, DigestConfig, GenerationConfig, GenerationSettings, PersonasConfig, PolicyConfig,
+    RuntimeConfig, required,
+};
+use conversation::ConversationMemory;
+use dedup::DedupCache;
+use digest::DigestScheduler;
+use event_details::EventDetailsCache;
+use health::HealthState;
+use identity::{BotIdentity, SenderKind};
+use llm::LLMClient;
+use model_routing::ModelRouter;
+use pipeline::{
+    DedupStage, HistoryStage, ModelStage, PersonaStage, PolicyStage, RateLimitStage, Request,
+    Responder, RetrievalStage, SummaryStage,
+};
+use policy::EventKind;
+use rate_limit::WorkQueue;
+use reload::{ConfigReloader, RuntimeHandle};
+use retrieval::Retriever;
+use slack_api::SlackApi;
 use slack_rs::{
-    Block, Event, MessageClient, SigningSecret, SlackEventHandler, Token, create_app_with_path,
+    Event, MessageClient, SigningSecret, SlackEventHandler, Token, create_app_with_path,
     events::{Message, Sender},
 };
-use std::net::SocketAddr;
-use tracing::{Level, info};
+use slash_command::SlashCommandState;
+use socket_mode::SocketModeClient;
+use std::process::ExitCode;
+use store::{MessageStore, StoredMessage};
+use streaming::StreamingReplier;
+use summarize::Summarizer;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tools::{CurrentTimeTool, FetchThreadTool, SearchMessagesTool, ToolRegistry, UserLookupTool};
+use tracing::info;
 use tracing_subscriber::FmtSubscriber;
-
-// LLMクライアント構造体
-#[derive(Clone)]
-struct LLMClient {
-    api_url: String,
-    operator_id: String,
-    api_token: String,
-}
-
-// モデル指定用の構造体
-#[derive(Clone)]
-pub struct LLMOptions<'a> {
-    pub model: &'a str,
+use transport::{HttpListener, Transport};
+use vector_index::{SemanticIndex, VectorIndex};
+
+// 設定ファイルから組み立てた、応答時に参照する設定
+struct Runtime {
+    models: ModelRouter,
+    generation: GenerationSettings,
+    personas: PersonasConfig,
+    bot: BotConfig,
+    policy: PolicyConfig,
+    digests: Vec<DigestConfig>,
 }
 
-impl<'a> Default for LLMOptions<'a> {
-    fn default() -> Self {
+impl Runtime {
+    fn new(config: &RuntimeConfig) -> Self {
         Self {
-            model: "google_ai:gemini-2.0-flash-exp",
+            models: ModelRouter::new(config.models.clone()),
+            generation: config.generation.clone(),
+            personas: config.personas.clone(),
+            bot: config.bot.clone(),
+            policy: config.policy.clone(),
+            digests: config.digests.clone(),
         }
     }
-}
 
-impl LLMClient {
-    fn new(api_url: String, operator_id: String, api_token: String) -> Self {
-        Self {
-            api_url,
-            operator_id,
-            api_token,
+    // チャンネルの生成パラメータに、割り当てられたペルソナのsystemプロンプトを適用する
+    fn generation_for(&self, store: &MessageStore, channel: &str) -> GenerationConfig {
+        let mut generation = self.generation.for_channel(channel);
+        match persona::active_persona(&self.personas, store, channel) {
+            Ok(Some(active)) => {
+                generation.system_prompt = Some(active.definition.system_prompt.clone());
+            }
+            Ok(None) => {}
+            Err(e) => info!("ペルソナの取得に失敗: {}", e),
         }
-    }
-
-    async fn get_response(
-        &self,
-        user_message: &str,
-        options: Option<LLMOptions<'_>>,
-    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-        let client = reqwest::Client::new();
-        let model = options
-            .map(|opt| opt.model)
-            .unwrap_or("google_ai:gemini-2.0-flash-exp");
-
-        let request_body = json!({
-            "model": model,
-            "messages": [
-                {
-                    "role": "user",
-                    "content": user_message
-                }
-            ]
-        });
-
-        let response = client
-            .post(&self.api_url)
-            .header("Accept", "application/json")
-            .header("x-operator-id", &self.operator_id)
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {}", self.api_token))
-            .json(&request_body)
-            .send()
-            .await?;
-
-        let response_json: Value = response.json().await?;
-        let content = response_json["choices"][0]["message"]["content"]
-            .as_str()
-            .unwrap_or("申し訳ありません。応答を生成できませんでした。")
-            .to_string();
-
-        Ok(content)
+        generation
     }
 }
 
 // メンションハンドラの定義
 #[derive(Clone)]
 struct MentionHandler {
-    llm_client: LLMClient,
+    identity: BotIdentity,
+    details: EventDetailsCache,
+    store: MessageStore,
+    retriever: Retriever,
+    runtime: RuntimeHandle,
+    responder: Responder,
 }
 
 impl MentionHandler {
-    fn new(llm_client: LLMClient) -> Self {
-        Self { llm_client }
+    fn new(
+        identity: BotIdentity,
+        details: EventDetailsCache,
+        store: MessageStore,
+        retriever: Retriever,
+        runtime: RuntimeHandle,
+        responder: Responder,
+    ) -> Self {
+        Self {
+            identity,
+            details,
+            store,
+            retriever,
+            runtime,
+            responder,
+        }
+    }
+
+    // stockmind自身の発言には応答しない。他のボットへの応答は設定による
+    fn should_respond(&self, sender_kind: &SenderKind) -> bool {
+        match sender_kind {
+            SenderKind::User(_) => true,
+            SenderKind::OtherBot(_) => self.runtime.current().bot.respond_to_other_bots,
+            SenderKind::Own => false,
+        }
     }
 }
 
@@ -100,6 +152,15 @@ impl SlackEventHandler for MentionHandler {
         event: Event,
         client: &MessageClient,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        let event_type = match &event {
+            Event::AppMention { .. } => "app_mention",
+            Event::Message(_) => "message",
+            _ => "other",
+        };
+        metrics::EVENTS_RECEIVED
+            .with_label_values(&[event_type])
+            .inc();
+
         match event {
             Event::AppMention {
                 channel, ts, text, ..
@@ -109,36 +170,24 @@ impl SlackEventHandler for MentionHandler {
                     channel, ts, text
                 );
 
-                // クローンを作成して非同期タスクで処理
-                let llm_client = self.llm_client.clone();
-                let client = client.clone();
-                let channel = channel.clone();
-                let ts = ts.clone();
-                let text = text.clone();
-
-                tokio::spawn(async move {
-                    // モデルを指定してLLM APIから応答を取得
-                    let options = Some(LLMOptions {
-                        model: "google_ai:gemini-2.0-flash-exp",
-                    });
+                let details = self.details.get(&channel, &ts);
+                let sender_kind = self
+                    .identity
+                    .classify(details.user.as_deref(), details.bot_id.as_deref());
+                if !self.should_respond(&sender_kind) {
+                    tracing::debug!("ボットからのメンションのため無視: {:?}", sender_kind);
+                    return Ok(());
+                }
 
-                    let result = llm_client.get_response(&text, options).await;
-                    let message = match result {
-                        Ok(response) => response,
-                        Err(e) => {
-                            info!("LLM APIからの応答取得に失敗: {}", e);
-                            "申し訳ありません。応答の生成に失敗しました。".to_string()
-                        }
-                    };
-
-                    if let Err(e) = client
-                        .reply_to_thread_with_blocks(&channel, &ts, vec![Block::Section {
-                            text: message,
-                        }])
-                        .await
-                    {
-                        info!("返信の送信に失敗: {}", e);
-                    }
+                // ワークスペースごとのモデル指定に使う。不明ならボットのワークスペースとみなす
+                let team_id = details.team.or_else(|| self.identity.team_id.clone());
+                self.responder.spawn(client, Request {
+                    kind: EventKind::Mention,
+                    channel,
+                    ts,
+                    text,
+                    team_id,
+                    user: Some(sender_kind.stored_sender(&self.identity)),
                 });
             }
             Event::Message(Message {
@@ -152,46 +201,58 @@ impl SlackEventHandler for MentionHandler {
                     "メッセージを受信: channel={}, text={}, team_id={}, sender={:?}, ts={}",
                     channel,
                     text,
-                    team_id.unwrap_or_default(),
+                    team_id.clone().unwrap_or_default(),
                     sender,
                     ts
                 );
 
-                if let Sender::User { id, .. } = sender {
-                    if id != "U05HWFCGZ1D" {
-                        let llm_client = self.llm_client.clone();
-                        let client = client.clone();
-                        let channel = channel.clone();
-                        let ts = ts.clone();
-                        let text = text.clone();
-
-                        tokio::spawn(async move {
-                            // モデルを指定してLLM APIから応答を取得
-                            let options = Some(LLMOptions {
-                                model: "google_ai:gemini-2.0-flash-exp",
-                            });
-
-                            let result = llm_client.get_response(&text, options).await;
-                            let message = match result {
-                                Ok(response) => response,
-                                Err(e) => {
-                                    info!("LLM APIからの応答取得に失敗: {}", e);
-                                    "申し訳ありません。応答の生成に失敗しました。".to_string()
-                                }
-                            };
-
-                            if let Err(e) = client
-                                .reply_to_thread_with_blocks(&channel, &ts, vec![Block::Section {
-                                    text: message,
-                                }])
-                                .await
-                            {
-                                info!("返信の送信に失敗: {}", e);
-                            }
-                        });
-                    } else {
-                        tracing::debug!("stockmind自身のメッセージのため無視");
-                    }
+                // 受信したメッセージを保存
+                let details = self.details.get(&channel, &ts);
+                let user = details.user.clone().or_else(|| match &sender {
+                    Sender::User { id, .. } => Some(id.clone()),
+                    _ => None,
+                });
+                let sender_kind = self
+                    .identity
+                    .classify(user.as_deref(), details.bot_id.as_deref());
+                let stored = StoredMessage {
+                    channel: channel.clone(),
+                    ts: ts.clone(),
+                    thread_ts: details.thread_ts,
+                    team_id,
+                    sender: sender_kind.stored_sender(&self.identity),
+                    text: text.clone(),
+                };
+                if let Err(e) = self.store.upsert(&stored) {
+                    info!("メッセージの保存に失敗: {}", e);
+                }
+
+                // 埋め込みを取得してベクトルインデックスに追加
+                if let Some(semantic) = self.retriever.semantic()
+                    && sender_kind != SenderKind::Own
+                {
+                    let semantic = semantic.clone();
+                    let channel = channel.clone();
+                    let ts = ts.clone();
+                    let text = text.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = semantic.add(&channel, &ts, &text).await {
+                            info!("ベクトルインデックスへの追加に失敗: {}", e);
+                        }
+                    });
+                }
+
+                if self.should_respond(&sender_kind) {
+                    self.responder.spawn(client, Request {
+                        kind: EventKind::Message,
+                        channel,
+                        ts,
+                        text,
+                        team_id: stored.team_id,
+                        user: Some(stored.sender),
+                    });
+                } else {
+                    tracing::debug!("ボットのメッセージのため無視: {:?}", sender_kind);
                 }
             }
             _ => info!("未対応のイベント: {:?}", event),
@@ -201,65 +262,326 @@ impl SlackEventHandler for MentionHandler {
 }
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> anyhow::Result<ExitCode> {
     dotenvy::dotenv().ok();
+    let cli = Cli::parse();
+
+    // 設定の読み込み(ファイル < 環境変数 < コマンドライン引数)
+    let loaded = RuntimeConfig::load(&cli.overrides);
+
+    if let Some(Command::Config {
+        action: ConfigCommand::Check,
+    }) = cli.command
+    {
+        check_config(loaded)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    let (config, config_path) = loaded?;
 
     // ロギングの初期化
     FmtSubscriber::builder()
-        .with_max_level(Level::INFO)
+        .with_max_level(config.log.max_level()?)
         .compact()
         .init();
 
     info!("メンション応答サーバーを起動します");
+    match &config_path {
+        Some(path) => info!("設定ファイルを読み込みました: {}", path.display()),
+        None => info!("設定ファイルがないため既定の設定を使用します"),
+    }
+    tracing::debug!("有効な設定: {:?}", config);
+    let runtime = RuntimeHandle::new(Runtime::new(&config));
+    info!(
+        "利用可能なモデル: {}",
+        runtime.current().models.allowed_models().join(", ")
+    );
+
+    // SIGHUPか設定ファイルの更新で実行時設定を再読み込みする
+    let limits = config.limits;
+    let reloader = ConfigReloader::new(runtime.clone(), cli.overrides, config_path, limits);
+    let users = reloader.user_limiter();
+    let channels = reloader.channel_limiter();
+    reloader.spawn()?;
+
+    // イベントの受信経路
+    let transport = Transport::from_config(&config)?;
+    metrics::init();
+
+    // SIGINT・SIGTERMで受け付けを止め、処理中の応答を待ってから終了する
+    let shutdown = CancellationToken::new();
+    shutdown::listen_for_signals(shutdown.clone())?;
+    let tasks = TaskTracker::new();
+
+    // Slack認証情報
+    let bot_token = required(&config.slack.bot_token, "SLACK_BOT_TOKEN")?.expose();
+    let slack_api = SlackApi::new(bot_token.to_string());
+    let bot_token = Token::new(bot_token.to_string());
+
+    // ボットトークンから自分自身のユーザーID・ボットIDを取得
+    let identity = slack_api.auth_test().await?;
+    info!(
+        "ボットの識別情報を取得しました: user_id={}, bot_id={}, team_id={}",
+        identity.user_id,
+        identity.bot_id.as_deref().unwrap_or("-"),
+        identity.team_id.as_deref().unwrap_or("-")
+    );
+
+    // LLM APIの設定
+    let llm = &config.llm;
+    let mut llm_client = LLMClient::new(
+        required(&llm.api_url, "API_URL")?.clone(),
+        required(&llm.operator_id, "OPERATOR_ID")?.clone(),
+        required(&llm.api_token, "API_TOKEN")?.expose().to_string(),
+    )
+    .with_retry_policy(llm.retry);
+    if let Some(embedding_url) = &llm.embedding_url {
+        llm_client = llm_client.with_embedding_url(embedding_url.clone());
+    }
 
-    // 環境変数からSlack認証情報を取得
-    let signing_secret =
-        std::env::var("SLACK_SIGNING_SECRET").expect("SLACK_SIGNING_SECRETが設定されていません");
-    let bot_token = std::env::var("SLACK_BOT_TOKEN").expect("SLACK_BOT_TOKENが設定されていません");
-    let bot_token = Token::new(bot_token);
-
-    let ngrok_domain = std::env::var("NGROK_DOMAIN").expect("NGROK_DOMAINが設定されていません");
-
-    // LLM APIの設定を環境変数から取得
-    let api_url = std::env::var("API_URL").expect("API_URLが設定されていません");
-    let operator_id = std::env::var("OPERATOR_ID").expect("OPERATOR_IDが設定されていません");
-    let api_token = std::env::var("API_TOKEN").expect("API_TOKENが設定されていません");
-
-    let llm_client = LLMClient::new(api_url, operator_id, api_token);
-
-    // ルーターの設定
-    let router = Router::new()
-        .route("/health", get(|| async { "OK" }))
-        .merge(create_app_with_path(
-            SigningSecret::new(signing_secret),
-            bot_token,
-            MentionHandler::new(llm_client),
-            "/push",
-        ));
-
-    // サーバーアドレスの設定
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    info!("サーバーを開始します: {}", addr);
-
-    let tun = ngrok::Session::builder()
-        // NGROKトークンを環境変数から読み込み
-        .authtoken_from_env()
-        // NGROKセッションの接続
-        .connect()
-        .await?
-        // HTTPエンドポイントのトンネルを開始
-        .http_endpoint()
-        .domain(ngrok_domain)
-        .listen()
-        .await?;
-
-    info!("Tunnel URL: {}", tun.url());
-
-    // サーバーの起動
-    axum::Server::builder(tun)
-        .serve(router.into_make_service_with_connect_info::<SocketAddr>())
-        .await
-        .unwrap();
+    // LLM呼び出しの待ち行列とユーザーごとの頻度制限は、イベントとスラッシュコマンドで共有する。
+    // 埋め込みの取得も同じゲートウェイを使うため同じ待ち行列に並ぶ
+    let queue = WorkQueue::new(&limits);
+
+    // メッセージストアを開く
+    let db_path = &config.storage.db_path;
+    let store = MessageStore::open(db_path)?;
+    info!("メッセージストアを開きました: {}", db_path);
+
+    // embedding_modelが設定されていればベクトル検索を有効にする
+    let semantic = match llm.embedding_model.clone() {
+        Some(model) => {
+            let index_path = &config.storage.vector_index_path;
+            let index = VectorIndex::open(index_path)?;
+            info!(
+                "ベクトルインデックスを開きました: {} ({}件, model={})",
+                index_path,
+                index.len(),
+                model
+            );
+            Some(SemanticIndex::new(llm_client.clone(), model, index).with_queue(queue.clone()))
+        }
+        None => None,
+    };
+
+    // 過去メッセージ検索の設定
+    let retrieval = config.retrieval;
+    let retriever = Retriever::new(
+        store.clone(),
+        slack_api.clone(),
+        semantic,
+        identity.user_id.clone(),
+        retrieval,
+    );
+
+    // 応答のストリーミング設定
+    let streaming = StreamingReplier::new(slack_api.clone(), config.streaming);
+
+    // スレッドの会話履歴の設定
+    let memory = ConversationMemory::new(slack_api.clone(), identity.clone(), config.conversation);
+
+    // スレッドとチャンネルの要約
+    let summarizer = Summarizer::new(
+        slack_api.clone(),
+        llm_client.clone(),
+        identity.clone(),
+        config.summary,
+    );
+
+    // 定期ダイジェストの投稿
+    DigestScheduler::new(
+        store.clone(),
+        slack_api.clone(),
+        summarizer.clone(),
+        identity.clone(),
+        runtime.clone(),
+    )
+    .with_queue(queue.clone())
+    .spawn();
+
+    // スラッシュコマンドの設定
+    let signing_secret = config
+        .slack
+        .signing_secret
+        .as_ref()
+        .map(|secret| secret.expose().to_string())
+        .unwrap_or_default();
+    let slash_commands = SlashCommandState {
+        signing_secret: signing_secret.clone(),
+        store: store.clone(),
+        slack_api: slack_api.clone(),
+        runtime: runtime.clone(),
+        llm_client: llm_client.clone(),
+        retriever: retriever.clone(),
+        queue: queue.clone(),
+        users: users.clone(),
+        summarizer: summarizer.clone(),
+        http: reqwest::Client::new(),
+        tasks: tasks.clone(),
+    };
+
+    // 再送・重複イベントの排除
+    let dedup = DedupCache::new(config.dedup.ttl());
+    // slack_rsのイベント型にないフィールド
+    let details = EventDetailsCache::default();
+
+    // [tools] enabledならLLMにツールを使わせる。
+    // メッセージを読むツールは過去メッセージ検索と同じ範囲に限る
+    let tools = if config.tools.enabled {
+        let tools = ToolRegistry::default()
+            .with_max_iterations(config.tools.max_iterations)
+            .with_tool(SearchMessagesTool {
+                store: store.clone(),
+                bot_user_id: identity.user_id.clone(),
+                scope: retrieval.scope,
+            })
+            .with_tool(FetchThreadTool {
+                slack_api: slack_api.clone(),
+                scope: retrieval.scope,
+            })
+            .with_tool(CurrentTimeTool)
+            .with_tool(UserLookupTool {
+                slack_api: slack_api.clone(),
+            });
+        info!("ツール呼び出しを有効にしました");
+        tools
+    } else {
+        ToolRegistry::default()
+    };
+
+    // 応答の処理の流れ。ステージは登録順に実行する
+    let responder = Responder::new(llm_client.clone(), streaming)
+        .with_queue(queue.clone())
+        .with_tools(tools)
+        .with_tasks(tasks.clone())
+        .with_stage(PolicyStage {
+            runtime: runtime.clone(),
+            store: store.clone(),
+            memory: memory.clone(),
+        })
+        .with_stage(DedupStage {
+            dedup: dedup.clone(),
+        })
+        .with_stage(RateLimitStage { users, channels })
+        .with_stage(ModelStage {
+            runtime: runtime.clone(),
+        })
+        .with_stage(SummaryStage {
+            summarizer,
+            queue: queue.clone(),
+        })
+        .with_stage(HistoryStage {
+            memory,
+            store: store.clone(),
+        })
+        .with_stage(RetrievalStage {
+            retriever: retriever.clone(),
+        })
+        .with_stage(PersonaStage {
+            runtime: runtime.clone(),
+            store: store.clone(),
+        });
+
+    // 準備状態の確認
+    let health = HealthState::new(
+        slack_api.clone(),
+        llm_client.clone(),
+        store.clone(),
+        queue.clone(),
+        runtime.clone(),
+        config.health,
+    );
+
+    let handler = MentionHandler::new(
+        identity,
+        details.clone(),
+        store,
+        retriever,
+        runtime,
+        responder,
+    );
+
+    let served = match transport {
+        // Socket Modeなら公開URLは不要
+        Transport::Socket(options) => {
+            info!("Socket Modeでイベントを受信します");
+            // イベントはWebSocketで受け取り、運用向けのエンドポイントだけHTTPで公開する
+            let ops = HttpListener::Bind {
+                addr: config.server.listen_addr()?,
+                tls: None,
+            };
+            let ops_shutdown = shutdown.clone();
+            let ops_router = ops_router(health);
+            tokio::spawn(async move {
+                if let Err(e) = ops.serve(ops_router, ops_shutdown).await {
+                    info!("運用向けのHTTPサーバーが停止しました: {:#}", e);
+                }
+            });
+            SocketModeClient::new(
+                options,
+                handler,
+                MessageClient::new(bot_token),
+                dedup,
+                details,
+                slash_commands,
+            )
+            .run(shutdown)
+            .await
+        }
+        Transport::Http(listener) => {
+            // ルーターの設定
+            let router = ops_router(health)
+                .merge(slash_command::router(
+                    slash_commands,
+                    &config.slack.command_path,
+                ))
+                .merge(
+                    create_app_with_path(
+                        SigningSecret::new(signing_secret.clone()),
+                        bot_token,
+                        handler,
+                        &config.slack.event_path,
+                    )
+                    .layer(middleware::from_fn_with_state(
+                        details,
+                        event_details::record_events,
+                    ))
+                    .layer(middleware::from_fn_with_state(dedup, dedup::dedup_events))
+                    // 署名を検証できたリクエストだけを重複排除と記録に使う
+                    .layer(middleware::from_fn_with_state(
+                        signing_secret,
+                        signature::verify_events,
+                    )),
+                );
+
+            // サーバーの起動
+            listener.serve(router, shutdown).await
+        }
+    };
+    if let Err(e) = &served {
+        info!("イベントの受信が異常終了しました: {:#}", e);
+    }
+
+    // 受け付けを止めたので、処理中の応答を待ってから終了する
+    let code = shutdown::drain(&tasks, config.server.shutdown_timeout()).await;
+    served?;
+    Ok(code)
+}
 
+// ヘルスチェックとメトリクス
+fn ops_router(health: HealthState) -> Router {
+    health::router(health).route("/metrics", get(metrics::handler))
+}
+
+// `stockmind config check`: 設定を検証し、シークレットを伏せて表示する
+fn check_config(
+    loaded: anyhow::Result<(RuntimeConfig, Option<std::path::PathBuf>)>,
+) -> anyhow::Result<()> {
+    let (config, path) = loaded?;
+    match path {
+        Some(path) => println!("設定ファイル: {}", path.display()),
+        None => println!("設定ファイル: なし(環境変数と既定値)"),
+    }
+    println!("{:#?}", config);
+    println!("設定に問題はありません");
     Ok(())
 }
@@ -0,0 +1,154 @@
+//! Prometheusのメトリクス
+//!
+//! `/metrics`でテキスト形式のメトリクスを公開する。ボットが黙って失敗していることに
+//! 気づけるよう、受信したイベント、送った応答、LLMの所要時間・エラー・トークン数、
+//! Slack APIの失敗、待ち行列の長さ、重複排除の件数を記録する。
+
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use serde_json::Value;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+fn register<C: Collector + Clone + 'static>(collector: C) -> C {
+    REGISTRY
+        .register(Box::new(collector.clone()))
+        .expect("メトリクスの名前は重複しない");
+    collector
+}
+
+fn counter_vec(name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    register(IntCounterVec::new(Opts::new(name, help), labels).expect("メトリクスの定義は正しい"))
+}
+
+/// 受信したSlackイベント数(type: app_mention / message / slash_command / other)
+pub static EVENTS_RECEIVED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    counter_vec(
+        "stockmind_events_received_total",
+        "受信したSlackイベント数",
+        &["type"],
+    )
+});
+
+/// Slackに送った応答の数
+pub static REPLIES_SENT: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(
+        IntCounter::new("stockmind_replies_sent_total", "Slackに送った応答の数")
+            .expect("メトリクスの定義は正しい"),
+    )
+});
+
+/// LLM呼び出しの所要時間(再試行を含む)
+pub static LLM_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "stockmind_llm_request_duration_seconds",
+                "LLM呼び出しの所要時間(再試行を含む)",
+            )
+            .buckets(vec![0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0]),
+            &["model", "outcome"],
+        )
+        .expect("メトリクスの定義は正しい"),
+    )
+});
+
+/// LLM呼び出しのエラー数(再試行しても失敗したもの)
+pub static LLM_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    counter_vec(
+        "stockmind_llm_errors_total",
+        "LLM呼び出しのエラー数",
+        &["model", "category"],
+    )
+});
+
+/// LLMが報告したトークン数(direction: input / output)
+pub static LLM_TOKENS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    counter_vec(
+        "stockmind_llm_tokens_total",
+        "LLMが報告したトークン数",
+        &["model", "direction"],
+    )
+});
+
+/// Slack APIの呼び出しの失敗数
+pub static SLACK_API_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    counter_vec(
+        "stockmind_slack_api_failures_total",
+        "Slack APIの呼び出しの失敗数",
+        &["method"],
+    )
+});
+
+/// LLM呼び出しの待ち行列に並んでいる(実行中を含む)リクエスト数
+pub static QUEUE_DEPTH: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(
+        IntGauge::new(
+            "stockmind_queue_depth",
+            "LLM呼び出しの待ち行列に並んでいるリクエスト数(実行中を含む)",
+        )
+        .expect("メトリクスの定義は正しい"),
+    )
+});
+
+/// 重複として捨てたイベント・応答の数(kind: event / reply)
+pub static DEDUP_HITS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    counter_vec(
+        "stockmind_dedup_hits_total",
+        "重複として捨てたイベント・応答の数",
+        &["kind"],
+    )
+});
+
+/// 一度も記録されていないメトリクスも出力されるよう、起動時にすべて登録する
+pub fn init() {
+    LazyLock::force(&EVENTS_RECEIVED);
+    LazyLock::force(&REPLIES_SENT);
+    LazyLock::force(&LLM_LATENCY);
+    LazyLock::force(&LLM_ERRORS);
+    LazyLock::force(&LLM_TOKENS);
+    LazyLock::force(&SLACK_API_FAILURES);
+    LazyLock::force(&QUEUE_DEPTH);
+    LazyLock::force(&DEDUP_HITS);
+}
+
+/// LLM呼び出しの結果を記録する。`error`は失敗した場合のエラーの種類
+pub fn record_llm_call(model: &str, elapsed: Duration, error: Option<&'static str>) {
+    let outcome = if error.is_some() { "error" } else { "ok" };
+    LLM_LATENCY
+        .with_label_values(&[model, outcome])
+        .observe(elapsed.as_secs_f64());
+    if let Some(category) = error {
+        LLM_ERRORS.with_label_values(&[model, category]).inc();
+    }
+}
+
+/// OpenAI形式の`usage`からトークン数を記録する
+pub fn record_tokens(model: &str, usage: &Value) {
+    if let Some(input) = usage["prompt_tokens"].as_u64() {
+        LLM_TOKENS
+            .with_label_values(&[model, "input"])
+            .inc_by(input);
+    }
+    if let Some(output) = usage["completion_tokens"].as_u64() {
+        LLM_TOKENS
+            .with_label_values(&[model, "output"])
+            .inc_by(output);
+    }
+}
+
+pub async fn handler() -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(e) = encoder.encode(&REGISTRY.gather(), &mut body) {
+        tracing::info!("メトリクスの出力に失敗: {}", e);
+    }
+    ([(CONTENT_TYPE, encoder.format_type().to_string())], body)
+}
@@ -0,0 +1,173 @@
+//! ワークスペース・チャンネルごとのモデル選択
+//!
+//! 設定ファイルのルーティングに従ってモデルを決め、メッセージ中の
+//! `--model <provider:model>`による指定は許可リストにあるものだけ受け付ける。
+
+use crate::config::ModelsConfig;
+use std::fmt;
+
+const OVERRIDE_FLAG: &str = "--model";
+
+// 許可されていないモデルが指定された
+#[derive(Debug, Clone)]
+pub struct ModelNotAllowed {
+    pub requested: String,
+    pub allowed: Vec<String>,
+}
+
+impl fmt::Display for ModelNotAllowed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "モデル `{}` は利用できません。利用できるモデル: {}",
+            self.requested,
+            self.allowed
+                .iter()
+                .map(|m| format!("`{}`", m))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelRouter {
+    config: ModelsConfig,
+}
+
+impl ModelRouter {
+    pub fn new(config: ModelsConfig) -> Self {
+        Self { config }
+    }
+
+    /// ワークスペース・チャンネルの指定がない場合のモデル
+    pub fn default_model(&self) -> &str {
+        &self.config.default
+    }
+
+    /// ルーティングで使われるモデルと許可リストを合わせた、指定可能なモデル
+    pub fn allowed_models(&self) -> Vec<String> {
+        let mut models = vec![self.config.default.clone()];
+        models.extend(self.config.workspaces.values().cloned());
+        models.extend(self.config.channels.values().cloned());
+        models.extend(self.config.allowed.iter().cloned());
+        models.sort();
+        models.dedup();
+        models
+    }
+
+    /// 使用するモデルを決める
+    ///
+    /// `requested`(メッセージ中の指定) > チャンネル > ワークスペース > 既定 の順に優先する。
+    pub fn resolve(
+        &self,
+        team_id: Option<&str>,
+        channel: &str,
+        requested: Option<&str>,
+    ) -> Result<String, ModelNotAllowed> {
+        if let Some(requested) = requested {
+            let allowed = self.allowed_models();
+            if !allowed.iter().any(|m| m == requested) {
+                return Err(ModelNotAllowed {
+                    requested: requested.to_string(),
+                    allowed,
+                });
+            }
+            return Ok(requested.to_string());
+        }
+
+        let model = self
+            .config
+            .channels
+            .get(channel)
+            .or_else(|| team_id.and_then(|team_id| self.config.workspaces.get(team_id)))
+            .unwrap_or(&self.config.default);
+        Ok(model.clone())
+    }
+}
+
+/// メッセージから`--model <name>`(または`--model=<name>`)を取り出す
+///
+/// 戻り値は指定されたモデル名と、指定を取り除いた本文。
+pub fn parse_model_override(text: &str) -> (Option<String>, String) {
+    let not_found = || (None, text.to_string());
+
+    let Some(start) = text
+        .match_indices(OVERRIDE_FLAG)
+        .map(|(i, _)| i)
+        .find(|&i| i == 0 || text[..i].ends_with(char::is_whitespace) || text[..i].ends_with('>'))
+    else {
+        return not_found();
+    };
+
+    let after = &text[start + OVERRIDE_FLAG.len()..];
+    let value = match after.strip_prefix('=') {
+        Some(value) => value,
+        None if after.starts_with(char::is_whitespace) => after.trim_start(),
+        None => return not_found(),
+    };
+    let value_len = value.find(char::is_whitespace).unwrap_or(value.len());
+    if value_len == 0 {
+        return not_found();
+    }
+
+    // valueはtextの末尾部分なので、長さの差から位置を求められる
+    let end = text.len() - value.len() + value_len;
+    let rest = format!("{} {}", text[..start].trim_end(), text[end..].trim_start());
+    (
+        Some(value[..value_len].to_string()),
+        rest.trim().to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(model: &str, rest: &str) -> (Option<String>, String) {
+        (Some(model.to_string()), rest.to_string())
+    }
+
+    #[test]
+    fn extracts_model_with_space_or_equals() {
+        assert_eq!(
+            parse_model_override("--model openai:gpt-4o 今日の予定は?"),
+            parsed("openai:gpt-4o", "今日の予定は?")
+        );
+        assert_eq!(
+            parse_model_override("今日の予定は? --model=openai:gpt-4o"),
+            parsed("openai:gpt-4o", "今日の予定は?")
+        );
+    }
+
+    #[test]
+    fn keeps_surrounding_text_and_mentions() {
+        assert_eq!(
+            parse_model_override("<@UBOT> --model openai:gpt-4o 要約して"),
+            parsed("openai:gpt-4o", "<@UBOT> 要約して")
+        );
+        assert_eq!(
+            parse_model_override("<@UBOT>--model openai:gpt-4o 要約して"),
+            parsed("openai:gpt-4o", "<@UBOT> 要約して")
+        );
+    }
+
+    #[test]
+    fn ignores_text_without_a_valid_flag() {
+        for text in [
+            "モデルの指定なし",
+            "--models openai:gpt-4o",
+            "foo--model openai:gpt-4o",
+            "--model",
+            "--model ",
+            "--model=",
+        ] {
+            assert_eq!(
+                parse_model_override(text),
+                (None, text.to_string()),
+                "{}",
+                text
+            );
+        }
+    }
+}
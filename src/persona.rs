@@ -0,0 +1,92 @@
+//! チャンネルごとのペルソナ(systemプロンプト)
+//!
+//! 設定ファイルで定義したペルソナをチャンネルに割り当てる。スラッシュコマンドで
+//! 切り替えた割り当てはストアに保存され、設定ファイルの割り当てより優先される。
+
+use crate::config::{PersonaDefinition, PersonasConfig};
+use crate::store::MessageStore;
+
+// channel_settingsのキー
+const SETTING_KEY: &str = "persona";
+
+// 割り当ての出どころ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PersonaSource {
+    /// スラッシュコマンドで設定された
+    Command,
+    /// 設定ファイルのチャンネル割り当て
+    Channel,
+    /// 設定ファイルの既定値
+    Default,
+}
+
+#[derive(Debug, Clone)]
+pub struct ActivePersona<'a> {
+    pub name: &'a str,
+    pub definition: &'a PersonaDefinition,
+    pub source: PersonaSource,
+}
+
+/// チャンネルで有効なペルソナを返す
+///
+/// ストアに保存された名前が設定ファイルから削除されている場合は無視する。
+pub fn active_persona<'a>(
+    config: &'a PersonasConfig,
+    store: &MessageStore,
+    channel: &str,
+) -> anyhow::Result<Option<ActivePersona<'a>>> {
+    let assigned = store.channel_setting(channel, SETTING_KEY)?;
+    let candidates = [
+        (assigned.as_deref(), PersonaSource::Command),
+        (
+            config.channels.get(channel).map(String::as_str),
+            PersonaSource::Channel,
+        ),
+        (config.default.as_deref(), PersonaSource::Default),
+    ];
+
+    for (name, source) in candidates {
+        let Some(name) = name else {
+            continue;
+        };
+        if let Some((name, definition)) = config.definitions.get_key_value(name) {
+            return Ok(Some(ActivePersona {
+                name,
+                definition,
+                source,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// チャンネルのペルソナを切り替える
+pub fn assign(
+    config: &PersonasConfig,
+    store: &MessageStore,
+    channel: &str,
+    name: &str,
+) -> Result<(), String> {
+    if !config.definitions.contains_key(name) {
+        return Err(format!(
+            "ペルソナ `{}` は定義されていません。利用できるペルソナ: {}",
+            name,
+            names(config).join(", ")
+        ));
+    }
+    store
+        .set_channel_setting(channel, SETTING_KEY, name)
+        .map_err(|e| format!("ペルソナの保存に失敗しました: {}", e))
+}
+
+/// スラッシュコマンドでの割り当てを解除し、設定ファイルの割り当てに戻す
+pub fn reset(store: &MessageStore, channel: &str) -> anyhow::Result<()> {
+    store.delete_channel_setting(channel, SETTING_KEY)
+}
+
+/// 定義されているペルソナ名(名前順)
+pub fn names(config: &PersonasConfig) -> Vec<&str> {
+    let mut names: Vec<&str> = config.definitions.keys().map(String::as_str).collect();
+    names.sort();
+    names
+}
@@ -0,0 +1,577 @@
+//! イベントを受けてから返信するまでの処理の流れ
+//!
+//! 受信したイベントを`Request`に正規化し、登録した`Stage`を順に通して
+//! プロンプトを組み立て、LLMの応答を後処理してスレッドに返信する。
+//! 新しいイベントの種類や前後処理(検索、ログ、マスキングなど)は
+//! `Stage`を追加するだけで全てのイベントに適用される。
+
+use crate::config::GenerationConfig;
+use crate::conversation::ConversationMemory;
+use crate::dedup::DedupCache;
+use crate::llm::{ChatMessage, DEFAULT_MODEL, LLMClient};
+use crate::metrics;
+use crate::model_routing::parse_model_override;
+use crate::policy::EventKind;
+use crate::rate_limit::{RateLimiter, WorkQueue};
+use crate::reload::RuntimeHandle;
+use crate::retrieval::{self, Retriever, Snippet};
+use crate::store::MessageStore;
+use crate::streaming::StreamingReplier;
+use crate::summarize::{self, Summarizer};
+use crate::tools::{ToolContext, ToolRegistry};
+use slack_rs::{Block, MessageClient};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_util::task::TaskTracker;
+use tracing::info;
+
+// 正規化したイベント
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub kind: EventKind,
+    pub channel: String,
+    pub ts: String,
+    pub text: String,
+    pub team_id: Option<String>,
+    /// 送信者
+    pub user: Option<String>,
+}
+
+// ステージ間で受け渡す、1回の応答の状態
+pub struct Turn {
+    pub request: Request,
+    /// モデル指定などを取り除いた質問文
+    pub question: String,
+    pub model: Option<String>,
+    pub generation: GenerationConfig,
+    pub snippets: Vec<Snippet>,
+    pub messages: Vec<ChatMessage>,
+}
+
+impl Turn {
+    fn new(request: Request) -> Self {
+        Self {
+            question: request.text.clone(),
+            request,
+            model: None,
+            generation: GenerationConfig::default(),
+            snippets: Vec::new(),
+            messages: Vec::new(),
+        }
+    }
+}
+
+// ステージの処理結果
+pub enum Flow {
+    /// 次のステージに進む
+    Continue,
+    /// 返信せずに終了する
+    Skip,
+    /// LLMを呼ばずにこのテキストを返信して終了する
+    Reply(String),
+}
+
+/// 処理の流れに差し込むステージ
+///
+/// `prepare`は登録順にLLMの呼び出し前に、`finish`は登録順に応答の後処理として呼ばれる。
+/// 失敗しても応答を続けられる場合はステージ内でログを出して`Continue`を返す。
+#[async_trait::async_trait]
+pub trait Stage: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn prepare(&self, _turn: &mut Turn) -> Flow {
+        Flow::Continue
+    }
+
+    fn finish(&self, _turn: &Turn, response: String) -> String {
+        response
+    }
+}
+
+#[derive(Clone)]
+pub struct Responder {
+    llm_client: LLMClient,
+    streaming: StreamingReplier,
+    stages: Vec<Arc<dyn Stage>>,
+    queue: WorkQueue,
+    tools: ToolRegistry,
+    tasks: TaskTracker,
+}
+
+impl Responder {
+    pub fn new(llm_client: LLMClient, streaming: StreamingReplier) -> Self {
+        Self {
+            llm_client,
+            streaming,
+            stages: Vec::new(),
+            queue: WorkQueue::default(),
+            tools: ToolRegistry::default(),
+            tasks: TaskTracker::new(),
+        }
+    }
+
+    /// 応答のタスクを`tasks`で追跡する(終了時に完了を待つため)
+    pub fn with_tasks(mut self, tasks: TaskTracker) -> Self {
+        self.tasks = tasks;
+        self
+    }
+
+    pub fn with_tools(mut self, tools: ToolRegistry) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    pub fn with_queue(mut self, queue: WorkQueue) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    pub fn with_stage(mut self, stage: impl Stage + 'static) -> Self {
+        self.stages.push(Arc::new(stage));
+        self
+    }
+
+    /// 非同期タスクでリクエストを処理する
+    pub fn spawn(&self, client: &MessageClient, request: Request) {
+        let responder = self.clone();
+        let client = client.clone();
+        self.tasks
+            .spawn(async move { responder.run(&client, request).await });
+    }
+
+    pub async fn run(&self, client: &MessageClient, request: Request) {
+        let mut turn = Turn::new(request);
+
+        for stage in &self.stages {
+            match stage.prepare(&mut turn).await {
+                Flow::Continue => {}
+                Flow::Skip => {
+                    tracing::debug!(
+                        "{}により応答を中止: channel={}, ts={}",
+                        stage.name(),
+                        turn.request.channel,
+                        turn.request.ts
+                    );
+                    return;
+                }
+                Flow::Reply(text) => {
+                    reply(client, &turn.request, text).await;
+                    return;
+                }
+            }
+        }
+
+        if turn.messages.is_empty() {
+            turn.messages.push(ChatMessage::user(turn.question.clone()));
+        }
+
+        // LLMの同時呼び出し数を制限する
+        let Some(slot) = self.queue.try_enqueue() else {
+            info!(
+                "待ち行列が満杯のため受け付けません: channel={}, ts={}",
+                turn.request.channel, turn.request.ts
+            );
+            let text = "ただいま混み合っています。しばらくしてからもう一度お試しください。";
+            reply(client, &turn.request, text.to_string()).await;
+            return;
+        };
+        let _permit = slot.start().await;
+        self.reply_with_llm(client, &turn).await;
+    }
+
+    // 全ステージの後処理を通す
+    fn finish(&self, turn: &Turn, response: String) -> String {
+        self.stages
+            .iter()
+            .fold(response, |response, stage| stage.finish(turn, response))
+    }
+
+    // LLMの応答を取得してスレッドに返信する
+    //
+    // ストリーミングが有効ならプレースホルダーを投稿してから逐次更新する。
+    // プレースホルダーを投稿できなければ応答全体を待ってから返信する。
+    // ツールが登録されている場合は、ツールの実行を挟むためストリーミングしない。
+    async fn reply_with_llm(&self, client: &MessageClient, turn: &Turn) {
+        let request = &turn.request;
+        let model = turn.model.as_deref().unwrap_or(DEFAULT_MODEL);
+        let options = Some(turn.generation.options(model));
+
+        if !self.tools.is_empty() {
+            let context = ToolContext {
+                channel: request.channel.clone(),
+                ts: request.ts.clone(),
+            };
+            let result = self
+                .tools
+                .run(
+                    &self.llm_client,
+                    turn.messages.clone(),
+                    turn.generation.options(model),
+                    &context,
+                )
+                .await;
+            let message = match result {
+                Ok(response) => self.finish(turn, response),
+                Err(e) => {
+                    info!("LLM APIからの応答取得に失敗: model={}, {}", model, e);
+                    e.user_message().to_string()
+                }
+            };
+            reply(client, request, message).await;
+            return;
+        }
+
+        if self.streaming.enabled() {
+            match self.streaming.start(&request.channel, &request.ts).await {
+                Ok(reply) => {
+                    let (tx, rx) = mpsc::unbounded_channel();
+                    let (result, ()) = tokio::join!(
+                        self.llm_client
+                            .stream_chat_response(&turn.messages, options, tx),
+                        reply.follow(rx)
+                    );
+                    let message = match result {
+                        Ok(response) => self.finish(turn, response),
+                        Err(e) => {
+                            info!("LLM APIからの応答取得に失敗: model={}, {}", model, e);
+                            e.user_message().to_string()
+                        }
+                    };
+                    match reply.finish(&message).await {
+                        Ok(()) => metrics::REPLIES_SENT.inc(),
+                        Err(e) => info!("返信の更新に失敗: {}", e),
+                    }
+                    return;
+                }
+                Err(e) => info!(
+                    "プレースホルダーの投稿に失敗したため通常の返信にします: {}",
+                    e
+                ),
+            }
+        }
+
+        let result = self
+            .llm_client
+            .get_chat_response(&turn.messages, options)
+            .await;
+        let message = match result {
+            Ok(response) => self.finish(turn, response),
+            Err(e) => {
+                info!("LLM APIからの応答取得に失敗: model={}, {}", model, e);
+                e.user_message().to_string()
+            }
+        };
+        reply(client, request, message).await;
+    }
+}
+
+async fn reply(client: &MessageClient, request: &Request, text: String) {
+    match client
+        .reply_to_thread_with_blocks(&request.channel, &request.ts, vec![Block::Section { text }])
+        .await
+    {
+        Ok(_) => metrics::REPLIES_SENT.inc(),
+        Err(e) => {
+            metrics::SLACK_API_FAILURES
+                .with_label_values(&["reply_to_thread"])
+                .inc();
+            info!("返信の送信に失敗: {}", e);
+        }
+    }
+}
+
+/// チャンネルの応答ポリシーでこのイベントに応答するかを判定する
+pub struct PolicyStage {
+    pub runtime: RuntimeHandle,
+    pub store: MessageStore,
+    pub memory: ConversationMemory,
+}
+
+#[async_trait::async_trait]
+impl Stage for PolicyStage {
+    fn name(&self) -> &'static str {
+        "応答ポリシー"
+    }
+
+    async fn prepare(&self, turn: &mut Turn) -> Flow {
+        let request = &turn.request;
+        let runtime = self.runtime.current();
+        let policy = match runtime.policy.for_channel(&self.store, &request.channel) {
+            Ok(policy) => policy,
+            Err(e) => {
+                info!("応答ポリシーの取得に失敗: {}", e);
+                return Flow::Skip;
+            }
+        };
+        let allowed = policy
+            .allows(
+                request.kind,
+                &request.channel,
+                &request.ts,
+                &request.text,
+                &self.memory,
+            )
+            .await;
+        if allowed { Flow::Continue } else { Flow::Skip }
+    }
+}
+
+/// 同じメッセージに一度だけ応答する
+///
+/// メンションはapp_mentionとmessageの両方のイベントで届くため、
+/// ポリシーで応答すると決まったものだけを応答済みとして記録する。
+pub struct DedupStage {
+    pub dedup: DedupCache,
+}
+
+#[async_trait::async_trait]
+impl Stage for DedupStage {
+    fn name(&self) -> &'static str {
+        "重複排除"
+    }
+
+    async fn prepare(&self, turn: &mut Turn) -> Flow {
+        if self
+            .dedup
+            .first_reply(&turn.request.channel, &turn.request.ts)
+        {
+            Flow::Continue
+        } else {
+            info!(
+                "応答済みのメッセージのため無視: channel={}, ts={}",
+                turn.request.channel, turn.request.ts
+            );
+            Flow::Skip
+        }
+    }
+}
+
+/// ユーザーとチャンネルごとの頻度制限
+///
+/// 制限を超えたときはメンションされた場合だけ待ち時間を返信し、
+/// それ以外は連投のたびに返信しないよう黙って見送る。
+pub struct RateLimitStage {
+    pub users: RateLimiter,
+    pub channels: RateLimiter,
+}
+
+#[async_trait::async_trait]
+impl Stage for RateLimitStage {
+    fn name(&self) -> &'static str {
+        "頻度制限"
+    }
+
+    async fn prepare(&self, turn: &mut Turn) -> Flow {
+        let request = &turn.request;
+        if let Some(user) = &request.user
+            && let Err(wait) = self.users.check(user)
+        {
+            info!("ユーザーの頻度制限: user={}, wait={:?}", user, wait);
+            return match request.kind {
+                EventKind::Mention => Flow::Reply(format!(
+                    "<@{}> 短時間にたくさんのリクエストをいただいたため、少し休ませてください。{}秒ほどしてからもう一度お試しください。",
+                    user,
+                    wait.as_secs().max(1)
+                )),
+                EventKind::Message => Flow::Skip,
+            };
+        }
+        if let Err(wait) = self.channels.check(&request.channel) {
+            // チャンネルの制限で断った分はユーザーの枠を消費させない
+            if let Some(user) = &request.user {
+                self.users.refund(user);
+            }
+            info!(
+                "チャンネルの頻度制限: channel={}, wait={:?}",
+                request.channel, wait
+            );
+            return match request.kind {
+                EventKind::Mention => Flow::Reply(format!(
+                    "このチャンネルでのリクエストが混み合っています。{}秒ほどしてからもう一度お試しください。",
+                    wait.as_secs().max(1)
+                )),
+                EventKind::Message => Flow::Skip,
+            };
+        }
+        Flow::Continue
+    }
+}
+
+/// メッセージ中のモデル指定とルーティング設定から使用するモデルを決める
+///
+/// 許可されていないモデルが指定された場合はその旨を返信して終了する。
+pub struct ModelStage {
+    pub runtime: RuntimeHandle,
+}
+
+#[async_trait::async_trait]
+impl Stage for ModelStage {
+    fn name(&self) -> &'static str {
+        "モデル選択"
+    }
+
+    async fn prepare(&self, turn: &mut Turn) -> Flow {
+        let (requested, question) = parse_model_override(&turn.request.text);
+        match self.runtime.current().models.resolve(
+            turn.request.team_id.as_deref(),
+            &turn.request.channel,
+            requested.as_deref(),
+        ) {
+            Ok(model) => {
+                turn.model = Some(model);
+                turn.question = question;
+                Flow::Continue
+            }
+            Err(e) => {
+                info!("モデルの指定を拒否: {}", e.requested);
+                Flow::Reply(e.to_string())
+            }
+        }
+    }
+}
+
+/// 「要約」で始まるメンションにスレッドまたはチャンネルの要約を返す
+pub struct SummaryStage {
+    pub summarizer: Summarizer,
+    pub queue: WorkQueue,
+}
+
+#[async_trait::async_trait]
+impl Stage for SummaryStage {
+    fn name(&self) -> &'static str {
+        "要約"
+    }
+
+    async fn prepare(&self, turn: &mut Turn) -> Flow {
+        let request = &turn.request;
+        if request.kind != EventKind::Mention {
+            return Flow::Continue;
+        }
+        let Some(hours) = summarize::parse_trigger(&turn.question) else {
+            return Flow::Continue;
+        };
+
+        let target = match self
+            .summarizer
+            .target_for(&request.channel, &request.ts, hours)
+            .await
+        {
+            Ok(target) => target,
+            Err(e) => {
+                info!("要約対象の取得に失敗: {}", e);
+                return Flow::Reply(summarize::failure_message(&e));
+            }
+        };
+        info!("要約を作成: {:?}", target);
+
+        let Some(slot) = self.queue.try_enqueue() else {
+            return Flow::Reply(
+                "ただいま混み合っています。しばらくしてからもう一度お試しください。".to_string(),
+            );
+        };
+        let _permit = slot.start().await;
+        let model = turn.model.as_deref().unwrap_or(DEFAULT_MODEL);
+        match self.summarizer.summarize(&target, model).await {
+            Ok(summary) => Flow::Reply(summary),
+            Err(e) => {
+                info!("要約に失敗: {}", e);
+                Flow::Reply(summarize::failure_message(&e))
+            }
+        }
+    }
+}
+
+/// スレッドの会話履歴を組み立てる
+pub struct HistoryStage {
+    pub memory: ConversationMemory,
+    pub store: MessageStore,
+}
+
+#[async_trait::async_trait]
+impl Stage for HistoryStage {
+    fn name(&self) -> &'static str {
+        "会話履歴"
+    }
+
+    async fn prepare(&self, turn: &mut Turn) -> Flow {
+        let request = &turn.request;
+        match self
+            .memory
+            .build_messages(&request.channel, &request.ts, &turn.question)
+            .await
+        {
+            Ok((key, messages)) => {
+                info!(
+                    "会話履歴を取得: channel={}, thread_ts={}, messages={}",
+                    key.channel,
+                    key.thread_ts,
+                    messages.len()
+                );
+                if let Err(e) =
+                    self.store
+                        .set_thread_ts(&request.channel, &request.ts, &key.thread_ts)
+                {
+                    info!("thread_tsの記録に失敗: {}", e);
+                }
+                turn.messages = messages;
+            }
+            Err(e) => {
+                info!("会話履歴の取得に失敗: {}", e);
+                turn.messages = vec![ChatMessage::user(turn.question.clone())];
+            }
+        }
+        Flow::Continue
+    }
+}
+
+/// 過去のメッセージから質問に関連するものを検索し、回答に出典を付ける
+pub struct RetrievalStage {
+    pub retriever: Retriever,
+}
+
+#[async_trait::async_trait]
+impl Stage for RetrievalStage {
+    fn name(&self) -> &'static str {
+        "過去メッセージ検索"
+    }
+
+    async fn prepare(&self, turn: &mut Turn) -> Flow {
+        turn.snippets = self
+            .retriever
+            .retrieve(&turn.request.channel, &turn.request.ts, &turn.question)
+            .await
+            .unwrap_or_else(|e| {
+                info!("過去メッセージの検索に失敗: {}", e);
+                Vec::new()
+            });
+        if let Some(context) = retrieval::context_message(&turn.snippets) {
+            turn.messages.insert(0, context);
+        }
+        Flow::Continue
+    }
+
+    fn finish(&self, turn: &Turn, response: String) -> String {
+        retrieval::append_citations(response, &turn.snippets)
+    }
+}
+
+/// チャンネルごとの生成パラメータに、割り当てられたペルソナのsystemプロンプトを適用する
+pub struct PersonaStage {
+    pub runtime: RuntimeHandle,
+    pub store: MessageStore,
+}
+
+#[async_trait::async_trait]
+impl Stage for PersonaStage {
+    fn name(&self) -> &'static str {
+        "ペルソナ"
+    }
+
+    async fn prepare(&self, turn: &mut Turn) -> Flow {
+        turn.generation = self
+            .runtime
+            .current()
+            .generation_for(&self.store, &turn.request.channel);
+        Flow::Continue
+    }
+}
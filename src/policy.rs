@@ -0,0 +1,271 @@
+//! 通常のメッセージに応答するかを決めるポリシー
+//!
+//! チャンネルごとに応答モードを設定ファイルで指定し、チャンネルの管理者は
+//! スラッシュコマンドで実行中に変更できる。LLMのタスクを起動する前に評価する。
+
+use crate::config::{ChannelPolicyConfig, PolicyConfig};
+use crate::conversation::ConversationMemory;
+use crate::store::MessageStore;
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+
+// channel_settingsのキー
+const MODE_KEY: &str = "respond_mode";
+const KEYWORDS_KEY: &str = "respond_keywords";
+
+// 質問らしさを判定する表現
+const QUESTION_MARKERS: &[&str] = &[
+    "教えて",
+    "ですか",
+    "ますか",
+    "でしょうか",
+    "どうすれば",
+    "どうやって",
+    "なぜ",
+    "なんで",
+];
+const QUESTION_WORDS: &[&str] = &[
+    "how", "what", "why", "when", "where", "who", "which", "can", "could", "is", "are", "does",
+    "do",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RespondMode {
+    /// メンションされたときだけ応答する
+    #[default]
+    MentionOnly,
+    /// DMにだけ応答する(チャンネルではメンションにも応答しない)
+    DmsOnly,
+    /// stockmindが返信したことのあるスレッドでは、メンションがなくても応答する
+    JoinedThreads,
+    /// すべてのメッセージに応答する
+    AllMessages,
+    /// キーワードを含むメッセージに応答する
+    Keywords,
+    /// 質問らしいメッセージに応答する
+    Questions,
+}
+
+impl RespondMode {
+    pub const ALL: [RespondMode; 6] = [
+        Self::MentionOnly,
+        Self::DmsOnly,
+        Self::JoinedThreads,
+        Self::AllMessages,
+        Self::Keywords,
+        Self::Questions,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::MentionOnly => "mention_only",
+            Self::DmsOnly => "dms_only",
+            Self::JoinedThreads => "joined_threads",
+            Self::AllMessages => "all_messages",
+            Self::Keywords => "keywords",
+            Self::Questions => "questions",
+        }
+    }
+}
+
+impl fmt::Display for RespondMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for RespondMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|mode| mode.as_str() == s)
+            .ok_or_else(|| format!("不明な応答モードです: {}", s))
+    }
+}
+
+// 評価するイベントの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Mention,
+    Message,
+}
+
+// 設定の出どころ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicySource {
+    Command,
+    Channel,
+    Default,
+}
+
+// チャンネルで有効なポリシー
+#[derive(Debug, Clone)]
+pub struct ChannelPolicy {
+    pub mode: RespondMode,
+    pub keywords: Vec<String>,
+    pub source: PolicySource,
+}
+
+impl PolicyConfig {
+    /// チャンネルで有効なポリシーを返す
+    ///
+    /// スラッシュコマンドで設定されたもの > 設定ファイルのチャンネル指定 > 既定 の順に優先する。
+    pub fn for_channel(
+        &self,
+        store: &MessageStore,
+        channel: &str,
+    ) -> anyhow::Result<ChannelPolicy> {
+        if let Some(mode) = store.channel_setting(channel, MODE_KEY)?
+            && let Ok(mode) = mode.parse::<RespondMode>()
+        {
+            let keywords = store
+                .channel_setting(channel, KEYWORDS_KEY)?
+                .map(|k| k.lines().map(str::to_string).collect())
+                .unwrap_or_default();
+            return Ok(ChannelPolicy {
+                mode,
+                keywords,
+                source: PolicySource::Command,
+            });
+        }
+
+        let (config, source) = match self.channels.get(channel) {
+            Some(config) => (config, PolicySource::Channel),
+            None => (&self.default, PolicySource::Default),
+        };
+        Ok(ChannelPolicy {
+            mode: config.mode,
+            keywords: config.keywords.clone(),
+            source,
+        })
+    }
+
+    /// ポリシーを変更できるユーザーか(設定ファイルの管理者リスト)
+    pub fn is_admin(&self, user: &str) -> bool {
+        self.admins.iter().any(|admin| admin == user)
+    }
+}
+
+impl ChannelPolicyConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.mode == RespondMode::Keywords && self.keywords.is_empty() {
+            anyhow::bail!("mode = \"keywords\"にはkeywordsの指定が必要です");
+        }
+        Ok(())
+    }
+}
+
+impl ChannelPolicy {
+    /// このイベントに応答するかを判定する
+    ///
+    /// `joined_threads`ではスレッドにstockmindの返信があるかをSlackに問い合わせる。
+    pub async fn allows(
+        &self,
+        kind: EventKind,
+        channel: &str,
+        ts: &str,
+        text: &str,
+        memory: &ConversationMemory,
+    ) -> bool {
+        // DMは1対1の会話なので常に応答する
+        if is_direct_message(channel) {
+            return true;
+        }
+
+        match (kind, self.mode) {
+            (EventKind::Mention, RespondMode::DmsOnly) => false,
+            (EventKind::Mention, _) => true,
+            (EventKind::Message, RespondMode::MentionOnly | RespondMode::DmsOnly) => false,
+            (EventKind::Message, RespondMode::AllMessages) => true,
+            (EventKind::Message, RespondMode::Keywords) => {
+                let text = text.to_lowercase();
+                self.keywords
+                    .iter()
+                    .any(|keyword| text.contains(&keyword.to_lowercase()))
+            }
+            (EventKind::Message, RespondMode::Questions) => looks_like_question(text),
+            (EventKind::Message, RespondMode::JoinedThreads) => {
+                match memory.bot_joined_thread(channel, ts).await {
+                    Ok(joined) => joined,
+                    Err(e) => {
+                        tracing::info!("スレッドの確認に失敗: {}", e);
+                        false
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// 実行中にチャンネルのポリシーを変更する
+pub fn assign(
+    store: &MessageStore,
+    channel: &str,
+    mode: RespondMode,
+    keywords: &[String],
+) -> anyhow::Result<()> {
+    store.set_channel_setting(channel, MODE_KEY, mode.as_str())?;
+    store.set_channel_setting(channel, KEYWORDS_KEY, &keywords.join("\n"))
+}
+
+/// 実行中の変更を取り消し、設定ファイルのポリシーに戻す
+pub fn reset(store: &MessageStore, channel: &str) -> anyhow::Result<()> {
+    store.delete_channel_setting(channel, MODE_KEY)?;
+    store.delete_channel_setting(channel, KEYWORDS_KEY)
+}
+
+fn is_direct_message(channel: &str) -> bool {
+    channel.starts_with('D')
+}
+
+fn looks_like_question(text: &str) -> bool {
+    let text = text.trim();
+    if text.ends_with('?') || text.ends_with('？') {
+        return true;
+    }
+    if QUESTION_MARKERS.iter().any(|marker| text.contains(marker)) {
+        return true;
+    }
+    let first_word = text
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    QUESTION_WORDS.contains(&first_word.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_questions() {
+        for text in [
+            "明日の会議は何時から?",
+            "明日の会議は何時から？",
+            "デプロイの手順を教えて",
+            "これは本番環境ですか",
+            "How do I rotate the token",
+            "  what is the status  ",
+        ] {
+            assert!(looks_like_question(text), "{}", text);
+        }
+    }
+
+    #[test]
+    fn ignores_statements() {
+        for text in [
+            "了解しました",
+            "デプロイが完了しました。",
+            "Thanks!",
+            "This is fine",
+            "",
+        ] {
+            assert!(!looks_like_question(text), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,300 @@
+//! LLM呼び出しの同時実行数と頻度の制限
+//!
+//! 混雑したチャンネルや連投するユーザーがLLMゲートウェイに大量のリクエストを
+//! 送らないよう、全体の同時実行数を制限する待ち行列と、ユーザー・チャンネルごとの
+//! トークンバケットで流量を抑える。
+
+use crate::metrics;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+// 件数がこれを超えたら満杯に戻ったバケットを掃除する
+const PURGE_THRESHOLD: usize = 10_000;
+
+// 設定ファイルでの指定
+//
+// ```toml
+// [limits]
+// max_concurrency = 4
+// queue_size = 32
+// user = { per_minute = 6, burst = 3 }
+// channel = { per_minute = 30, burst = 10 }
+// ```
+//
+// per_minuteを0にするとその制限を無効にする
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitOptions {
+    /// LLMを同時に呼び出す上限
+    pub max_concurrency: usize,
+    /// 実行待ちにできるリクエストの上限
+    pub queue_size: usize,
+    pub user: BucketRate,
+    pub channel: BucketRate,
+}
+
+impl Default for LimitOptions {
+    fn default() -> Self {
+        Self {
+            max_concurrency: 4,
+            queue_size: 32,
+            user: BucketRate {
+                per_minute: 6,
+                burst: 3,
+            },
+            channel: BucketRate {
+                per_minute: 30,
+                burst: 10,
+            },
+        }
+    }
+}
+
+// トークンバケットの補充速度と容量
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BucketRate {
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+impl BucketRate {
+    fn capacity(&self) -> f64 {
+        self.burst.max(1) as f64
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        self.per_minute as f64 / 60.0
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// キー(ユーザーIDやチャンネルID)ごとのトークンバケット
+#[derive(Clone)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+    rate: Arc<Mutex<BucketRate>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: BucketRate) -> Self {
+        Self {
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            rate: Arc::new(Mutex::new(rate)),
+        }
+    }
+
+    /// 頻度を変更する。既存のバケットの残量はそのまま新しい容量で頭打ちにする
+    pub fn set_rate(&self, rate: BucketRate) {
+        *self.rate.lock().unwrap_or_else(|e| e.into_inner()) = rate;
+    }
+
+    /// トークンを1つ消費する。足りなければ次に使えるまでの時間を返す
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        let rate = *self.rate.lock().unwrap_or_else(|e| e.into_inner());
+        if rate.per_minute == 0 {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let capacity = rate.capacity();
+        let refill = rate.refill_per_sec();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= PURGE_THRESHOLD {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated_at).as_secs_f64() * refill
+                    < capacity
+            });
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated_at: now,
+        });
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill).min(capacity);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / refill))
+        }
+    }
+
+    /// `check`で消費したトークンを1つ戻す。後段の制限で断ったときに使う
+    pub fn refund(&self, key: &str) {
+        let rate = *self.rate.lock().unwrap_or_else(|e| e.into_inner());
+        if rate.per_minute == 0 {
+            return;
+        }
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(bucket) = buckets.get_mut(key) {
+            bucket.tokens = (bucket.tokens + 1.0).min(rate.capacity());
+        }
+    }
+}
+
+/// LLM呼び出しの待ち行列
+///
+/// 同時に実行できるのは`max_concurrency`件までで、それを超えた分は
+/// `queue_size`件まで順番を待つ。待ち行列も満杯なら受け付けない。
+#[derive(Clone)]
+pub struct WorkQueue {
+    permits: Arc<Semaphore>,
+    pending: Arc<AtomicUsize>,
+    capacity: usize,
+}
+
+impl Default for WorkQueue {
+    fn default() -> Self {
+        Self::new(&LimitOptions::default())
+    }
+}
+
+impl WorkQueue {
+    pub fn new(options: &LimitOptions) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(options.max_concurrency)),
+            pending: Arc::new(AtomicUsize::new(0)),
+            capacity: options.max_concurrency + options.queue_size,
+        }
+    }
+
+    /// 並んでいる(実行中を含む)リクエスト数
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    /// 並べるリクエスト数の上限
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// 待ち行列に並ぶ。満杯ならNoneを返す
+    pub fn try_enqueue(&self) -> Option<QueueSlot> {
+        self.pending
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |pending| {
+                (pending < self.capacity).then_some(pending + 1)
+            })
+            .ok()?;
+        metrics::QUEUE_DEPTH.inc();
+        Some(QueueSlot {
+            queue: self.clone(),
+        })
+    }
+}
+
+/// 待ち行列の順番。破棄すると列から抜ける
+pub struct QueueSlot {
+    queue: WorkQueue,
+}
+
+impl QueueSlot {
+    /// 実行できる順番が来るまで待つ
+    pub async fn start(&self) -> OwnedSemaphorePermit {
+        self.queue
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("セマフォは閉じられない")
+    }
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        self.queue.pending.fetch_sub(1, Ordering::AcqRel);
+        metrics::QUEUE_DEPTH.dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: BucketRate = BucketRate {
+        per_minute: 6,
+        burst: 3,
+    };
+
+    #[test]
+    fn allows_burst_then_reports_wait() {
+        let limiter = RateLimiter::new(RATE);
+        for _ in 0..RATE.burst {
+            assert_eq!(limiter.check("U1"), Ok(()));
+        }
+
+        // 1分に6回なので、次のトークンまでおよそ10秒
+        let wait = limiter.check("U1").unwrap_err();
+        assert!(wait > Duration::from_secs(9), "{:?}", wait);
+        assert!(wait <= Duration::from_secs(10), "{:?}", wait);
+    }
+
+    #[test]
+    fn refund_returns_a_consumed_token() {
+        let limiter = RateLimiter::new(RATE);
+        for _ in 0..RATE.burst {
+            assert_eq!(limiter.check("U1"), Ok(()));
+        }
+        assert!(limiter.check("U1").is_err());
+
+        limiter.refund("U1");
+        assert_eq!(limiter.check("U1"), Ok(()));
+        assert!(limiter.check("U1").is_err());
+
+        // 容量を超えては戻さない
+        let limiter = RateLimiter::new(RATE);
+        assert_eq!(limiter.check("U2"), Ok(()));
+        limiter.refund("U2");
+        limiter.refund("U2");
+        for _ in 0..RATE.burst {
+            assert_eq!(limiter.check("U2"), Ok(()));
+        }
+        assert!(limiter.check("U2").is_err());
+    }
+
+    #[test]
+    fn keeps_separate_buckets_per_key() {
+        let limiter = RateLimiter::new(RATE);
+        for _ in 0..RATE.burst {
+            limiter.check("U1").unwrap();
+        }
+        assert!(limiter.check("U1").is_err());
+        assert_eq!(limiter.check("U2"), Ok(()));
+    }
+
+    #[test]
+    fn zero_rate_disables_limit() {
+        let limiter = RateLimiter::new(BucketRate {
+            per_minute: 0,
+            burst: 1,
+        });
+        for _ in 0..100 {
+            assert_eq!(limiter.check("U1"), Ok(()));
+        }
+    }
+
+    #[test]
+    fn set_rate_caps_existing_buckets() {
+        let limiter = RateLimiter::new(RATE);
+        limiter.check("U1").unwrap();
+        limiter.set_rate(BucketRate {
+            per_minute: 6,
+            burst: 1,
+        });
+        assert_eq!(limiter.check("U1"), Ok(()));
+        assert!(limiter.check("U1").is_err());
+    }
+}
@@ -0,0 +1,168 @@
+//! 実行時設定の再読み込み
+//!
+//! チャンネルのポリシー、ペルソナ、モデルのルーティング、頻度制限を再起動せずに
+//! 差し替える。SIGHUPを受け取るか設定ファイルの更新を検知したら読み込み直し、
+//! 検証に失敗した場合は以前の設定を使い続ける。Slackやサーバー、LLM APIの接続情報、
+//! 保存先、ログレベル、同時実行数の変更は再起動後に反映される。
+
+use crate::Runtime;
+use crate::cli::ConfigOverrides;
+use crate::config::RuntimeConfig;
+use crate::rate_limit::{LimitOptions, RateLimiter};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{SignalKind, signal};
+use tracing::info;
+
+// 設定ファイルの更新を確認する間隔
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 差し替え可能な実行時設定
+#[derive(Clone)]
+pub struct RuntimeHandle {
+    current: Arc<RwLock<Arc<Runtime>>>,
+}
+
+impl RuntimeHandle {
+    pub fn new(runtime: Runtime) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(Arc::new(runtime))),
+        }
+    }
+
+    /// 現在の設定を取得する。取得後に差し替えられても、取得した設定は変わらない
+    pub fn current(&self) -> Arc<Runtime> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn replace(&self, runtime: Runtime) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(runtime);
+    }
+}
+
+pub struct ConfigReloader {
+    handle: RuntimeHandle,
+    overrides: ConfigOverrides,
+    path: Option<PathBuf>,
+    limits: LimitOptions,
+    users: RateLimiter,
+    channels: RateLimiter,
+}
+
+impl ConfigReloader {
+    /// `limits`は起動時の制限。頻度制限は`users`と`channels`に反映する
+    pub fn new(
+        handle: RuntimeHandle,
+        overrides: ConfigOverrides,
+        path: Option<PathBuf>,
+        limits: LimitOptions,
+    ) -> Self {
+        Self {
+            handle,
+            overrides,
+            path,
+            limits,
+            users: RateLimiter::new(limits.user),
+            channels: RateLimiter::new(limits.channel),
+        }
+    }
+
+    /// ユーザーごとの頻度制限
+    pub fn user_limiter(&self) -> RateLimiter {
+        self.users.clone()
+    }
+
+    /// チャンネルごとの頻度制限
+    pub fn channel_limiter(&self) -> RateLimiter {
+        self.channels.clone()
+    }
+
+    /// SIGHUPと設定ファイルの更新をバックグラウンドで監視する
+    pub fn spawn(self) -> anyhow::Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            let mut modified = self.modified();
+            let mut interval = tokio::time::interval(WATCH_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = hangup.recv() => {
+                        info!("SIGHUPを受信したため設定を再読み込みします");
+                    }
+                    _ = interval.tick() => {
+                        let latest = self.modified();
+                        if latest == modified {
+                            continue;
+                        }
+                        modified = latest;
+                        info!("設定ファイルの更新を検知したため再読み込みします");
+                    }
+                }
+                self.reload();
+            }
+        });
+        Ok(())
+    }
+
+    // 設定ファイルの更新時刻
+    fn modified(&self) -> Option<SystemTime> {
+        let path = self.path.as_ref()?;
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    // 起動時に読み込んだ設定ファイルから読み直す。ファイルが消えていたり
+    // 不正だったりする場合は、既定値に戻さず以前の設定を使い続ける
+    fn reload(&self) {
+        let config = match RuntimeConfig::load_from(self.path.as_deref(), &self.overrides) {
+            Ok(config) => config,
+            Err(e) => {
+                info!(
+                    "設定の再読み込みに失敗したため以前の設定を使い続けます: {:#}",
+                    e
+                );
+                return;
+            }
+        };
+
+        self.users.set_rate(config.limits.user);
+        self.channels.set_rate(config.limits.channel);
+        if config.limits.max_concurrency != self.limits.max_concurrency
+            || config.limits.queue_size != self.limits.queue_size
+        {
+            info!("max_concurrencyとqueue_sizeの変更は再起動後に反映されます");
+        }
+
+        let runtime = Runtime::new(&config);
+        info!(
+            "設定を再読み込みしました: モデル={}",
+            runtime.models.allowed_models().join(", ")
+        );
+        self.handle.replace(runtime);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_current_runtime_when_config_file_is_missing() {
+        let mut config = RuntimeConfig::default();
+        config.models.default = "openai:gpt-4o".to_string();
+        let handle = RuntimeHandle::new(Runtime::new(&config));
+        let path =
+            std::env::temp_dir().join(format!("stockmind-missing-{}.toml", std::process::id()));
+
+        let reloader = ConfigReloader::new(
+            handle.clone(),
+            ConfigOverrides::default(),
+            Some(path),
+            config.limits,
+        );
+        reloader.reload();
+        assert_eq!(handle.current().models.default_model(), "openai:gpt-4o");
+    }
+}
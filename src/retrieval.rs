@@ -0,0 +1,349 @@
+//! 過去のSlackメッセージを使った検索拡張生成(RAG)
+//!
+//! メンションされた質問でメッセージストアを全文検索し(埋め込みが有効なら
+//! ベクトル検索の結果もReciprocal Rank Fusionで統合して)、上位のメッセージを
+//! パーマリンク付きでプロンプトに差し込む。返信中で`[1]`のように引用された
+//! メッセージは末尾に参照リンクとして列挙する。
+
+use crate::llm::ChatMessage;
+use crate::slack_api::SlackApi;
+use crate::store::{MessageStore, StoredMessage};
+use crate::vector_index::SemanticIndex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+// プロンプトに含める1件あたりの最大文字数
+const SNIPPET_MAX_CHARS: usize = 300;
+
+// Reciprocal Rank Fusionの定数
+const RRF_K: f32 = 60.0;
+
+// 検索対象の範囲
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetrievalScope {
+    /// 質問されたチャンネルのみ
+    Channel,
+    /// 保存されている全チャンネル
+    ///
+    /// 質問者が閲覧できるとは限らないため、質問されたチャンネル以外は
+    /// 公開チャンネルのメッセージだけを使う。
+    Workspace,
+}
+
+impl FromStr for RetrievalScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "channel" => Ok(Self::Channel),
+            "workspace" => Ok(Self::Workspace),
+            other => Err(format!(
+                "不明な検索範囲です: {} (channel, workspaceのいずれか)",
+                other
+            )),
+        }
+    }
+}
+
+// 設定ファイルでの指定
+//
+// ```toml
+// [retrieval]
+// top_k = 5
+// scope = "channel"   # channel / workspace
+// ```
+//
+// `workspace`ではプライベートチャンネルやDMの内容が他のチャンネルに漏れないよう、
+// 質問されたチャンネル以外は公開チャンネルに限る。公開かどうかは`conversations.info`
+// で確認するため、ボットに`channels:read`などの権限が必要になる。
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetrievalOptions {
+    pub top_k: usize,
+    pub scope: RetrievalScope,
+}
+
+impl Default for RetrievalOptions {
+    fn default() -> Self {
+        Self {
+            top_k: 5,
+            scope: RetrievalScope::Channel,
+        }
+    }
+}
+
+// プロンプトに差し込む検索結果
+#[derive(Debug, Clone)]
+pub struct Snippet {
+    pub message: StoredMessage,
+    pub permalink: String,
+}
+
+#[derive(Clone)]
+pub struct Retriever {
+    store: MessageStore,
+    slack_api: SlackApi,
+    semantic: Option<SemanticIndex>,
+    bot_user_id: String,
+    options: RetrievalOptions,
+    // チャンネルIDごとの公開チャンネルかどうか
+    public_channels: Arc<Mutex<HashMap<String, bool>>>,
+}
+
+impl Retriever {
+    pub fn new(
+        store: MessageStore,
+        slack_api: SlackApi,
+        semantic: Option<SemanticIndex>,
+        bot_user_id: String,
+        options: RetrievalOptions,
+    ) -> Self {
+        Self {
+            store,
+            slack_api,
+            semantic,
+            bot_user_id,
+            options,
+            public_channels: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// ベクトル検索が有効ならそのインデックスを返す
+    pub fn semantic(&self) -> Option<&SemanticIndex> {
+        self.semantic.as_ref()
+    }
+
+    /// 質問に関連する過去のメッセージを検索する
+    pub async fn retrieve(
+        &self,
+        channel: &str,
+        ts: &str,
+        question: &str,
+    ) -> anyhow::Result<Vec<Snippet>> {
+        if self.options.top_k == 0 {
+            return Ok(Vec::new());
+        }
+
+        let scope = match self.options.scope {
+            RetrievalScope::Channel => Some(channel),
+            RetrievalScope::Workspace => None,
+        };
+        let top_k = self.options.top_k;
+        let full_text = self
+            .store
+            .search(question, scope, &self.bot_user_id, ts, top_k)?;
+        let full_text = self.visible_from(channel, full_text).await;
+
+        let Some(semantic) = &self.semantic else {
+            return self.with_permalinks(full_text).await;
+        };
+
+        // ベクトル検索の結果をストアの内容と突き合わせ、全文検索と同じ条件で絞り込む
+        let hits = match semantic.search(question, top_k * 2, scope).await {
+            Ok(hits) => hits,
+            Err(e) => {
+                debug!("ベクトル検索に失敗したため全文検索のみ使用します: {}", e);
+                Vec::new()
+            }
+        };
+        let mut similar = Vec::new();
+        for hit in hits {
+            if hit.ts == ts {
+                continue;
+            }
+            if let Some(message) = self.store.get(&hit.channel, &hit.ts)?
+                && message.sender != self.bot_user_id
+            {
+                similar.push(message);
+            }
+        }
+
+        let similar = self.visible_from(channel, similar).await;
+
+        let messages = fuse(vec![full_text, similar], top_k);
+        self.with_permalinks(messages).await
+    }
+
+    // 質問されたチャンネル以外のメッセージは公開チャンネルのものだけを残す
+    async fn visible_from(
+        &self,
+        channel: &str,
+        messages: Vec<StoredMessage>,
+    ) -> Vec<StoredMessage> {
+        let mut visible = Vec::with_capacity(messages.len());
+        for message in messages {
+            if message.channel == channel || self.is_public(&message.channel).await {
+                visible.push(message);
+            }
+        }
+        visible
+    }
+
+    // 確認できなかったチャンネルは非公開として扱い、次回また確認する
+    async fn is_public(&self, channel: &str) -> bool {
+        let cached = self
+            .public_channels
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(channel)
+            .copied();
+        if let Some(public) = cached {
+            return public;
+        }
+
+        let info = match self.slack_api.conversations_info(channel).await {
+            Ok(info) => info,
+            Err(e) => {
+                debug!("チャンネルの公開範囲を確認できないため除外します: {}", e);
+                return false;
+            }
+        };
+        let public = info["is_channel"].as_bool() == Some(true)
+            && info["is_private"].as_bool() != Some(true);
+        self.public_channels
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(channel.to_string(), public);
+        public
+    }
+
+    async fn with_permalinks(&self, messages: Vec<StoredMessage>) -> anyhow::Result<Vec<Snippet>> {
+        let mut snippets = Vec::with_capacity(messages.len());
+        for message in messages {
+            let permalink = match self
+                .slack_api
+                .chat_get_permalink(&message.channel, &message.ts)
+                .await
+            {
+                Ok(permalink) => permalink,
+                Err(e) => {
+                    debug!("パーマリンクの取得に失敗したため組み立てます: {}", e);
+                    fallback_permalink(&message.channel, &message.ts)
+                }
+            };
+            snippets.push(Snippet { message, permalink });
+        }
+        Ok(snippets)
+    }
+}
+
+/// 複数の順位付きリストをReciprocal Rank Fusionで1つにまとめる
+fn fuse(rankings: Vec<Vec<StoredMessage>>, k: usize) -> Vec<StoredMessage> {
+    let mut scores: HashMap<(String, String), (f32, StoredMessage)> = HashMap::new();
+    for ranking in rankings {
+        for (rank, message) in ranking.into_iter().enumerate() {
+            let score = 1.0 / (RRF_K + rank as f32 + 1.0);
+            scores
+                .entry((message.channel.clone(), message.ts.clone()))
+                .or_insert((0.0, message))
+                .0 += score;
+        }
+    }
+
+    let mut fused: Vec<(f32, StoredMessage)> = scores.into_values().collect();
+    fused.sort_by(|a, b| b.0.total_cmp(&a.0));
+    fused.into_iter().take(k).map(|(_, m)| m).collect()
+}
+
+/// 検索結果をLLMに渡すsystemメッセージにする
+pub fn context_message(snippets: &[Snippet]) -> Option<ChatMessage> {
+    if snippets.is_empty() {
+        return None;
+    }
+
+    let mut content = String::from(
+        "以下はこのSlackワークスペースの過去のメッセージから検索した参考情報です。\
+         質問に関係する場合はこれらを根拠に回答し、使用したメッセージは本文中で[1]のように番号で引用してください。\
+         関係しない場合は無視してください。\n",
+    );
+    for (i, snippet) in snippets.iter().enumerate() {
+        let text: String = snippet
+            .message
+            .text
+            .chars()
+            .take(SNIPPET_MAX_CHARS)
+            .collect();
+        content.push_str(&format!(
+            "\n[{}] <#{}> {} (ts={}): {}",
+            i + 1,
+            snippet.message.channel,
+            snippet.message.sender,
+            snippet.message.ts,
+            text.replace('\n', " ")
+        ));
+    }
+    Some(ChatMessage::system(content))
+}
+
+/// 返信中で引用された番号の参照リンクを末尾に追記する
+pub fn append_citations(reply: String, snippets: &[Snippet]) -> String {
+    let cited: Vec<(usize, &Snippet)> = snippets
+        .iter()
+        .enumerate()
+        .map(|(i, snippet)| (i + 1, snippet))
+        .filter(|(n, _)| reply.contains(&format!("[{}]", n)))
+        .collect();
+    if cited.is_empty() {
+        return reply;
+    }
+
+    let mut reply = reply;
+    reply.push_str("\n\n*参照*");
+    for (n, snippet) in cited {
+        reply.push_str(&format!(
+            "\n[{}] <{}|メッセージを開く>",
+            n, snippet.permalink
+        ));
+    }
+    reply
+}
+
+fn fallback_permalink(channel: &str, ts: &str) -> String {
+    format!(
+        "https://slack.com/archives/{}/p{}",
+        channel,
+        ts.replace('.', "")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(ts: &str) -> StoredMessage {
+        StoredMessage {
+            channel: "C1".to_string(),
+            ts: ts.to_string(),
+            thread_ts: None,
+            team_id: None,
+            sender: "U1".to_string(),
+            text: format!("message {}", ts),
+        }
+    }
+
+    fn timestamps(messages: &[StoredMessage]) -> Vec<&str> {
+        messages.iter().map(|m| m.ts.as_str()).collect()
+    }
+
+    #[test]
+    fn fuse_prefers_messages_ranked_in_both_lists() {
+        let full_text = vec![message("a"), message("b"), message("c")];
+        let semantic = vec![message("b"), message("d")];
+        let fused = fuse(vec![full_text, semantic], 10);
+        assert_eq!(timestamps(&fused), ["b", "a", "d", "c"]);
+    }
+
+    #[test]
+    fn fuse_limits_results_to_k() {
+        let fused = fuse(
+            vec![vec![message("a"), message("b")], vec![message("c")]],
+            2,
+        );
+        assert_eq!(fused.len(), 2);
+        assert_eq!(fuse(Vec::new(), 5).len(), 0);
+    }
+}
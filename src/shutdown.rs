@@ -0,0 +1,64 @@
+//! 終了処理
+//!
+//! SIGINTかSIGTERMを受け取ったら新しいイベントの受け付けを止め、処理中の応答が
+//! 終わるのを`[server] shutdown_timeout_secs`まで待ってから終了する。期限までに
+//! 終わらなかった場合は終了コード1で終了する。待っている間にもう一度シグナルを
+//! 受け取った場合はすぐに終了する。
+
+use std::process::ExitCode;
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::info;
+
+// 2回目のシグナルで強制終了する場合の終了コード(128 + SIGINT)
+const FORCED_EXIT_CODE: i32 = 130;
+
+/// SIGINT・SIGTERMを受け取ったら`shutdown`を取り消す
+pub fn listen_for_signals(shutdown: CancellationToken) -> anyhow::Result<()> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = interrupt.recv() => info!("SIGINTを受信したため終了します"),
+            _ = terminate.recv() => info!("SIGTERMを受信したため終了します"),
+        }
+        shutdown.cancel();
+
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+        info!("再度シグナルを受信したため、処理中の応答を待たずに終了します");
+        std::process::exit(FORCED_EXIT_CODE);
+    });
+    Ok(())
+}
+
+/// 処理中のタスクが終わるのを`timeout`まで待つ
+pub async fn drain(tasks: &TaskTracker, timeout: Duration) -> ExitCode {
+    tasks.close();
+    if tasks.is_empty() {
+        return ExitCode::SUCCESS;
+    }
+
+    info!(
+        "処理中の応答を待っています: {}件 (最大{}秒)",
+        tasks.len(),
+        timeout.as_secs()
+    );
+    match tokio::time::timeout(timeout, tasks.wait()).await {
+        Ok(()) => {
+            info!("処理中の応答がすべて完了しました");
+            ExitCode::SUCCESS
+        }
+        Err(_) => {
+            info!(
+                "期限までに完了しなかった応答を中断して終了します: {}件",
+                tasks.len()
+            );
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! Slackのリクエスト署名の検証
+//!
+//! イベントAPIとスラッシュコマンドのリクエストを署名シークレットで検証する。
+//! イベントAPIはslack_rsも検証するが、その手前のミドルウェア(重複排除や
+//! イベントのフィールドの記録)が偽造されたリクエストの内容を使わないよう、
+//! 先にここで検証してから渡す。ボディは上限を設けて読み込む。
+
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hmac::{Hmac, Mac};
+use hyper::body::HttpBody;
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+// リプレイ攻撃を防ぐため、これより古いリクエストは拒否する
+const MAX_REQUEST_AGE_SECS: i64 = 60 * 5;
+
+// 読み込むリクエストボディの上限
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// Slackのリクエスト署名(`X-Slack-Signature`)を検証する
+pub fn verify_signature(
+    signing_secret: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<(), &'static str> {
+    let timestamp = headers
+        .get("X-Slack-Request-Timestamp")
+        .and_then(|v| v.to_str().ok())
+        .ok_or("X-Slack-Request-Timestampがありません")?;
+    let signature = headers
+        .get("X-Slack-Signature")
+        .and_then(|v| v.to_str().ok())
+        .ok_or("X-Slack-Signatureがありません")?;
+
+    let sent_at: i64 = timestamp.parse().map_err(|_| "タイムスタンプが不正です")?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    if (now - sent_at).abs() > MAX_REQUEST_AGE_SECS {
+        return Err("リクエストが古すぎます");
+    }
+
+    let expected = signature
+        .strip_prefix("v0=")
+        .and_then(|hex| hex::decode(hex).ok())
+        .ok_or("署名の形式が不正です")?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_secret.as_bytes())
+        .map_err(|_| "署名シークレットが不正です")?;
+    mac.update(b"v0:");
+    mac.update(timestamp.as_bytes());
+    mac.update(b":");
+    mac.update(body);
+    mac.verify_slice(&expected)
+        .map_err(|_| "署名が一致しません")
+}
+
+/// リクエストボディを上限まで読み込む。上限を超えたら413を返す
+pub async fn read_body(mut body: Body) -> Result<Bytes, StatusCode> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| {
+            info!("リクエストの読み込みに失敗: {}", e);
+            StatusCode::BAD_REQUEST
+        })?;
+        if bytes.len() + chunk.len() > MAX_BODY_BYTES {
+            info!("リクエストが大きすぎます: {}バイト超", MAX_BODY_BYTES);
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(Bytes::from(bytes))
+}
+
+/// イベントAPIのリクエストの署名を検証し、検証できたものだけを後段に渡す
+pub async fn verify_events(
+    State(signing_secret): State<String>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let (parts, body) = request.into_parts();
+    let bytes = match read_body(body).await {
+        Ok(bytes) => bytes,
+        Err(status) => return status.into_response(),
+    };
+    if let Err(e) = verify_signature(&signing_secret, &parts.headers, &bytes) {
+        info!("イベントの署名検証に失敗: {}", e);
+        return (StatusCode::UNAUTHORIZED, e).into_response();
+    }
+
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "8f742231b10e8888abcd99yyyzzz85a5";
+    const BODY: &[u8] = b"command=%2Fstockmind&text=help&channel_id=C1&user_id=U1";
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    fn signed_headers(secret: &str, timestamp: i64, body: &[u8]) -> HeaderMap {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("v0:{}:", timestamp).as_bytes());
+        mac.update(body);
+        let signature = format!("v0={}", hex::encode(mac.finalize().into_bytes()));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "X-Slack-Request-Timestamp",
+            timestamp.to_string().parse().unwrap(),
+        );
+        headers.insert("X-Slack-Signature", signature.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn accepts_valid_signature() {
+        let headers = signed_headers(SECRET, now(), BODY);
+        assert_eq!(verify_signature(SECRET, &headers, BODY), Ok(()));
+    }
+
+    #[test]
+    fn rejects_tampered_body_and_wrong_secret() {
+        let headers = signed_headers(SECRET, now(), BODY);
+        assert_eq!(
+            verify_signature(SECRET, &headers, b"command=%2Fstockmind&text=policy"),
+            Err("署名が一致しません")
+        );
+
+        let headers = signed_headers("another-secret", now(), BODY);
+        assert_eq!(
+            verify_signature(SECRET, &headers, BODY),
+            Err("署名が一致しません")
+        );
+    }
+
+    #[test]
+    fn rejects_stale_timestamp() {
+        let headers = signed_headers(SECRET, now() - MAX_REQUEST_AGE_SECS - 10, BODY);
+        assert_eq!(
+            verify_signature(SECRET, &headers, BODY),
+            Err("リクエストが古すぎます")
+        );
+    }
+
+    #[test]
+    fn rejects_missing_or_malformed_headers() {
+        assert_eq!(
+            verify_signature(SECRET, &HeaderMap::new(), BODY),
+            Err("X-Slack-Request-Timestampがありません")
+        );
+
+        let mut headers = signed_headers(SECRET, now(), BODY);
+        headers.remove("X-Slack-Signature");
+        assert_eq!(
+            verify_signature(SECRET, &headers, BODY),
+            Err("X-Slack-Signatureがありません")
+        );
+
+        headers.insert("X-Slack-Signature", "v1=abcd".parse().unwrap());
+        assert_eq!(
+            verify_signature(SECRET, &headers, BODY),
+            Err("署名の形式が不正です")
+        );
+
+        headers.insert("X-Slack-Request-Timestamp", "yesterday".parse().unwrap());
+        assert_eq!(
+            verify_signature(SECRET, &headers, BODY),
+            Err("タイムスタンプが不正です")
+        );
+    }
+
+    #[tokio::test]
+    async fn read_body_rejects_oversized_body() {
+        let body = Body::from(vec![b'a'; MAX_BODY_BYTES]);
+        assert_eq!(read_body(body).await.unwrap().len(), MAX_BODY_BYTES);
+
+        let body = Body::from(vec![b'a'; MAX_BODY_BYTES + 1]);
+        assert_eq!(read_body(body).await, Err(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+}
@@ -0,0 +1,300 @@
+//! Slack Web APIの薄いクライアント
+//!
+//! slack_rsの`MessageClient`が提供していないAPI(スレッド履歴の取得など)を
+//! ボットトークンで直接呼び出す。
+
+use crate::identity::BotIdentity;
+use crate::metrics;
+use anyhow::{Context, anyhow};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+const SLACK_API_BASE: &str = "https://slack.com/api";
+
+// conversations.repliesの1ページあたりの件数
+const REPLIES_PAGE_SIZE: &str = "200";
+
+#[derive(Clone)]
+pub struct SlackApi {
+    http: reqwest::Client,
+    bot_token: String,
+}
+
+// conversations.* が返すメッセージ
+#[derive(Debug, Clone, Deserialize)]
+pub struct SlackMessage {
+    pub ts: String,
+    #[serde(default)]
+    pub thread_ts: Option<String>,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub bot_id: Option<String>,
+    #[serde(default)]
+    pub text: String,
+    #[serde(default)]
+    pub blocks: Vec<Value>,
+    /// スレッドの親メッセージなら返信の数
+    #[serde(default)]
+    pub reply_count: usize,
+    /// スレッドの親メッセージなら最新の返信のts
+    #[serde(default)]
+    pub latest_reply: Option<String>,
+}
+
+impl SlackMessage {
+    /// 本文を取得する。`text`が空の場合はBlock Kitのsectionから組み立てる
+    pub fn plain_text(&self) -> String {
+        if !self.text.trim().is_empty() {
+            return self.text.clone();
+        }
+        self.blocks
+            .iter()
+            .filter_map(|block| block["text"]["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn is_from(&self, identity: &BotIdentity) -> bool {
+        identity.is_own(self.user.as_deref(), self.bot_id.as_deref())
+    }
+}
+
+impl SlackApi {
+    pub fn new(bot_token: String) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            bot_token,
+        }
+    }
+
+    async fn get(&self, method: &str, params: &[(&str, &str)]) -> anyhow::Result<Value> {
+        let request = self
+            .http
+            .get(format!("{}/{}", SLACK_API_BASE, method))
+            .query(params);
+        self.call(method, request).await
+    }
+
+    async fn post(&self, method: &str, body: &Value) -> anyhow::Result<Value> {
+        let request = self
+            .http
+            .post(format!("{}/{}", SLACK_API_BASE, method))
+            .json(body);
+        self.call(method, request).await
+    }
+
+    // 送信して`ok`を確認する。失敗した呼び出しはメトリクスに記録する
+    async fn call(&self, method: &str, request: reqwest::RequestBuilder) -> anyhow::Result<Value> {
+        let result = async {
+            let response: Value = request
+                .bearer_auth(&self.bot_token)
+                .send()
+                .await
+                .with_context(|| format!("{}の呼び出しに失敗しました", method))?
+                .json()
+                .await
+                .with_context(|| format!("{}の応答を解析できませんでした", method))?;
+            check_ok(method, response)
+        }
+        .await;
+        if result.is_err() {
+            metrics::SLACK_API_FAILURES
+                .with_label_values(&[method])
+                .inc();
+        }
+        result
+    }
+
+    /// ボットトークンの持ち主(stockmind自身)の識別情報を取得する
+    pub async fn auth_test(&self) -> anyhow::Result<BotIdentity> {
+        let response = self.get("auth.test", &[]).await?;
+        let user_id = response["user_id"]
+            .as_str()
+            .ok_or_else(|| anyhow!("auth.testの応答にuser_idがありません"))?
+            .to_string();
+        Ok(BotIdentity {
+            user_id,
+            bot_id: response["bot_id"].as_str().map(str::to_string),
+            team_id: response["team_id"].as_str().map(str::to_string),
+        })
+    }
+
+    /// ユーザーの情報を取得する(`user`オブジェクトを返す)
+    pub async fn users_info(&self, user: &str) -> anyhow::Result<Value> {
+        let response = self.get("users.info", &[("user", user)]).await?;
+        Ok(response["user"].clone())
+    }
+
+    /// チャンネルの情報を取得する(`channel`オブジェクトを返す)
+    pub async fn conversations_info(&self, channel: &str) -> anyhow::Result<Value> {
+        let response = self
+            .get("conversations.info", &[("channel", channel)])
+            .await?;
+        Ok(response["channel"].clone())
+    }
+
+    /// スレッドのメッセージを古い順に取得する
+    ///
+    /// `ts`にはスレッドの親メッセージか、スレッド内の任意のメッセージを指定できる。
+    /// `limit`件を超える場合は、先頭(親メッセージ)と新しい方の`limit - 1`件を返す。
+    pub async fn conversations_replies(
+        &self,
+        channel: &str,
+        ts: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<SlackMessage>> {
+        let limit = limit.max(1);
+        let mut messages: Vec<SlackMessage> = Vec::new();
+        let mut cursor = String::new();
+        // 古い順にしか返らないため、新しい方を残すには最後のページまでたどる
+        loop {
+            let (page, next) = self
+                .replies_page(channel, ts, REPLIES_PAGE_SIZE, &cursor)
+                .await?;
+            messages.extend(page);
+            if messages.len() > limit {
+                let excess = messages.len() - limit;
+                messages.drain(1..=excess);
+            }
+            match next {
+                Some(next) => cursor = next,
+                None => break,
+            }
+        }
+        Ok(messages)
+    }
+
+    /// スレッドの先頭のメッセージだけを取得する
+    pub async fn conversations_replies_head(
+        &self,
+        channel: &str,
+        ts: &str,
+    ) -> anyhow::Result<Option<SlackMessage>> {
+        let (page, _) = self.replies_page(channel, ts, "1", "").await?;
+        Ok(page.into_iter().next())
+    }
+
+    // conversations.repliesの1ページ分と次のページのカーソル
+    async fn replies_page(
+        &self,
+        channel: &str,
+        ts: &str,
+        limit: &str,
+        cursor: &str,
+    ) -> anyhow::Result<(Vec<SlackMessage>, Option<String>)> {
+        let response = self
+            .get("conversations.replies", &[
+                ("channel", channel),
+                ("ts", ts),
+                ("limit", limit),
+                ("cursor", cursor),
+            ])
+            .await?;
+        let page: Vec<SlackMessage> = serde_json::from_value(response["messages"].clone())
+            .context("conversations.repliesのメッセージを解析できませんでした")?;
+        let next = response["response_metadata"]["next_cursor"]
+            .as_str()
+            .filter(|next| !next.is_empty())
+            .map(str::to_string);
+        Ok((page, next))
+    }
+
+    /// チャンネルの`oldest`以降のメッセージを古い順に取得する
+    ///
+    /// スレッドへの返信は含まれない(親メッセージのみ)。`limit`件に達するまでページをたどる。
+    pub async fn conversations_history(
+        &self,
+        channel: &str,
+        oldest: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<SlackMessage>> {
+        let mut messages: Vec<SlackMessage> = Vec::new();
+        let mut cursor = String::new();
+        while messages.len() < limit {
+            let page_size = (limit - messages.len()).min(200).to_string();
+            let response = self
+                .get("conversations.history", &[
+                    ("channel", channel),
+                    ("oldest", oldest),
+                    ("limit", &page_size),
+                    ("cursor", &cursor),
+                ])
+                .await?;
+            let page: Vec<SlackMessage> = serde_json::from_value(response["messages"].clone())
+                .context("conversations.historyのメッセージを解析できませんでした")?;
+            messages.extend(page);
+
+            match response["response_metadata"]["next_cursor"].as_str() {
+                Some(next) if !next.is_empty() => cursor = next.to_string(),
+                _ => break,
+            }
+        }
+        // 新しい順に返るので古い順に並べ替える
+        messages.reverse();
+        Ok(messages)
+    }
+
+    /// メッセージのパーマリンクを取得する
+    pub async fn chat_get_permalink(&self, channel: &str, ts: &str) -> anyhow::Result<String> {
+        let response = self
+            .get("chat.getPermalink", &[
+                ("channel", channel),
+                ("message_ts", ts),
+            ])
+            .await?;
+        response["permalink"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("chat.getPermalinkの応答にpermalinkがありません"))
+    }
+
+    /// テキストを投稿し、投稿したメッセージのtsを返す
+    ///
+    /// `thread_ts`を指定するとそのスレッドに返信する。
+    pub async fn chat_post_message(
+        &self,
+        channel: &str,
+        thread_ts: Option<&str>,
+        text: &str,
+    ) -> anyhow::Result<String> {
+        let mut body = json!({
+            "channel": channel,
+            "text": text,
+        });
+        if let Some(thread_ts) = thread_ts {
+            body["thread_ts"] = json!(thread_ts);
+        }
+        let response = self.post("chat.postMessage", &body).await?;
+        response["ts"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("chat.postMessageの応答にtsがありません"))
+    }
+
+    /// 投稿済みメッセージのテキストを書き換える
+    pub async fn chat_update(&self, channel: &str, ts: &str, text: &str) -> anyhow::Result<()> {
+        self.post(
+            "chat.update",
+            &json!({
+                "channel": channel,
+                "ts": ts,
+                "text": text,
+            }),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+// Slack APIの`ok`を確認し、失敗なら`error`をエラーにする
+fn check_ok(method: &str, response: Value) -> anyhow::Result<Value> {
+    if response["ok"].as_bool() != Some(true) {
+        return Err(anyhow!(
+            "{}がエラーを返しました: {}",
+            method,
+            response["error"].as_str().unwrap_or("unknown_error")
+        ));
+    }
+    Ok(response)
+}
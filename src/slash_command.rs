@@ -0,0 +1,583 @@
+//! Slackのスラッシュコマンド
+//!
+//! `/stockmind <サブコマンド> ...`を受け付ける。イベントAPIと同じ署名シークレットで
+//! リクエストを検証してから処理する。LLMを呼ぶサブコマンドはSlackの3秒の制限に
+//! 収まらないため、すぐに受付だけを返し、結果は`response_url`に送る。
+
+use crate::llm::{ChatMessage, LLMClient};
+use crate::metrics;
+use crate::model_routing::parse_model_override;
+use crate::persona::{self, PersonaSource};
+use crate::policy::{self, PolicySource, RespondMode};
+use crate::rate_limit::{RateLimiter, WorkQueue};
+use crate::reload::RuntimeHandle;
+use crate::retrieval::{self, Retriever};
+use crate::signature::verify_signature;
+use crate::slack_api::SlackApi;
+use crate::store::MessageStore;
+use crate::summarize::{self, Summarizer, SummaryTarget};
+use axum::{
+    Json, Router,
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use tokio_util::task::TaskTracker;
+use tracing::info;
+
+// searchで表示する1件あたりの最大文字数
+const SEARCH_PREVIEW_CHARS: usize = 120;
+
+pub struct SlashCommandState {
+    pub signing_secret: String,
+    pub store: MessageStore,
+    pub slack_api: SlackApi,
+    pub runtime: RuntimeHandle,
+    pub llm_client: LLMClient,
+    pub retriever: Retriever,
+    pub queue: WorkQueue,
+    pub users: RateLimiter,
+    pub summarizer: Summarizer,
+    /// response_urlへの送信用
+    pub http: reqwest::Client,
+    /// 遅延応答のタスク(終了時に完了を待つ)
+    pub tasks: TaskTracker,
+}
+
+// Slackから送られるフォームの内容
+#[derive(Debug, Deserialize)]
+pub struct SlashCommand {
+    pub command: String,
+    #[serde(default)]
+    pub text: String,
+    pub channel_id: String,
+    pub user_id: String,
+    #[serde(default)]
+    pub team_id: Option<String>,
+    #[serde(default)]
+    pub response_url: String,
+}
+
+// コマンドへの応答
+struct CommandReply {
+    text: String,
+    /// trueならチャンネル全員に表示する
+    public: bool,
+}
+
+impl CommandReply {
+    fn ephemeral(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            public: false,
+        }
+    }
+
+    fn public(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            public: true,
+        }
+    }
+
+    fn payload(&self) -> serde_json::Value {
+        json!({
+            "response_type": if self.public { "in_channel" } else { "ephemeral" },
+            "text": self.text,
+        })
+    }
+}
+
+pub fn router(state: SlashCommandState, path: &str) -> Router {
+    Router::new()
+        .route(path, post(handle))
+        .with_state(Arc::new(state))
+}
+
+async fn handle(
+    State(state): State<Arc<SlashCommandState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if let Err(e) = verify_signature(&state.signing_secret, &headers, &body) {
+        info!("スラッシュコマンドの署名検証に失敗: {}", e);
+        return (StatusCode::UNAUTHORIZED, e).into_response();
+    }
+
+    let command: SlashCommand = match serde_urlencoded::from_bytes(&body) {
+        Ok(command) => command,
+        Err(e) => {
+            info!("スラッシュコマンドの解析に失敗: {}", e);
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+    };
+    Json(respond(&state, command).await).into_response()
+}
+
+/// 検証済みのコマンドを処理し、Slackに返す応答のJSONを組み立てる
+///
+/// Socket Modeではこの応答をenvelopeのackに載せて返す。
+pub async fn respond(state: &Arc<SlashCommandState>, command: SlashCommand) -> serde_json::Value {
+    info!(
+        "スラッシュコマンドを受信: command={}, text={}, channel={}, user={}",
+        command.command, command.text, command.channel_id, command.user_id
+    );
+    metrics::EVENTS_RECEIVED
+        .with_label_values(&["slash_command"])
+        .inc();
+    dispatch(state, command).await.payload()
+}
+
+async fn dispatch(state: &Arc<SlashCommandState>, command: SlashCommand) -> CommandReply {
+    let command = &command;
+    let mut args = command.text.split_whitespace();
+    match args.next() {
+        Some("ask") => ask_command(state, command),
+        Some("search") => search_command(state, command),
+        Some("summarize") => summarize_command(state, command, args.next()),
+        Some("persona") => persona_command(state, command, args.next()),
+        Some("policy") => policy_command(state, command, args.collect()).await,
+        Some("help") | None => CommandReply::ephemeral(help_text(state, &command.command)),
+        Some(other) => CommandReply::ephemeral(format!(
+            "不明なサブコマンドです: `{}`\n\n{}",
+            other,
+            help_text(state, &command.command)
+        )),
+    }
+}
+
+// サブコマンドの後ろの引数部分
+fn arguments<'a>(command: &'a SlashCommand, subcommand: &str) -> &'a str {
+    command
+        .text
+        .trim_start()
+        .strip_prefix(subcommand)
+        .unwrap_or_default()
+        .trim()
+}
+
+// 時間のかかる処理を非同期タスクで実行し、結果をresponse_urlに送る
+fn defer<F>(state: &Arc<SlashCommandState>, command: &SlashCommand, task: F) -> CommandReply
+where
+    F: Future<Output = CommandReply> + Send + 'static,
+{
+    if command.response_url.is_empty() {
+        return CommandReply::ephemeral("response_urlがないため処理できません。");
+    }
+
+    let http = state.http.clone();
+    let response_url = command.response_url.clone();
+    state.tasks.spawn(async move {
+        let reply = task.await;
+        let result = http
+            .post(&response_url)
+            .json(&reply.payload())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match result {
+            Ok(_) => metrics::REPLIES_SENT.inc(),
+            Err(e) => {
+                metrics::SLACK_API_FAILURES
+                    .with_label_values(&["response_url"])
+                    .inc();
+                info!("response_urlへの送信に失敗: {}", e);
+            }
+        }
+    });
+    CommandReply::ephemeral("処理しています。少々お待ちください…")
+}
+
+// ユーザーの頻度制限を確認する
+fn throttled(state: &SlashCommandState, user: &str) -> Option<CommandReply> {
+    let wait = state.users.check(user).err()?;
+    Some(CommandReply::ephemeral(format!(
+        "短時間にたくさんのリクエストをいただいたため、少し休ませてください。{}秒ほどしてからもう一度お試しください。",
+        wait.as_secs().max(1)
+    )))
+}
+
+// LLMに問い合わせる。待ち行列が満杯なら断る
+async fn complete(
+    state: &SlashCommandState,
+    messages: &[ChatMessage],
+    channel: &str,
+    model: &str,
+) -> Result<String, String> {
+    let Some(slot) = state.queue.try_enqueue() else {
+        return Err(
+            "ただいま混み合っています。しばらくしてからもう一度お試しください。".to_string(),
+        );
+    };
+    let _permit = slot.start().await;
+    let generation = state
+        .runtime
+        .current()
+        .generation_for(&state.store, channel);
+    state
+        .llm_client
+        .get_chat_response(messages, Some(generation.options(model)))
+        .await
+        .map_err(|e| {
+            info!("LLM APIからの応答取得に失敗: model={}, {}", model, e);
+            e.user_message().to_string()
+        })
+}
+
+// `ask [--public] [--model <名前>] <質問>`
+fn ask_command(state: &Arc<SlashCommandState>, command: &SlashCommand) -> CommandReply {
+    let mut question = arguments(command, "ask");
+    let public = match question.strip_prefix("--public") {
+        Some(rest) => {
+            question = rest.trim_start();
+            true
+        }
+        None => false,
+    };
+    let (requested, question) = parse_model_override(question);
+    if question.is_empty() {
+        return CommandReply::ephemeral(format!(
+            "使い方: `{} ask [--public] <質問>`",
+            command.command
+        ));
+    }
+    let model = match state.runtime.current().models.resolve(
+        command.team_id.as_deref(),
+        &command.channel_id,
+        requested.as_deref(),
+    ) {
+        Ok(model) => model,
+        Err(e) => return CommandReply::ephemeral(e.to_string()),
+    };
+    if let Some(reply) = throttled(state, &command.user_id) {
+        return reply;
+    }
+
+    let task_state = state.clone();
+    let channel = command.channel_id.clone();
+    let user = command.user_id.clone();
+    defer(state, command, async move {
+        let state = task_state;
+        let snippets = state
+            .retriever
+            .retrieve(&channel, "", &question)
+            .await
+            .unwrap_or_else(|e| {
+                info!("過去メッセージの検索に失敗: {}", e);
+                Vec::new()
+            });
+        let mut messages = vec![ChatMessage::user(question.clone())];
+        if let Some(context) = retrieval::context_message(&snippets) {
+            messages.insert(0, context);
+        }
+
+        match complete(&state, &messages, &channel, &model).await {
+            Ok(answer) => {
+                let answer = retrieval::append_citations(answer, &snippets);
+                let text = format!("<@{}> の質問: {}\n\n{}", user, question, answer);
+                if public {
+                    CommandReply::public(text)
+                } else {
+                    CommandReply::ephemeral(text)
+                }
+            }
+            Err(message) => CommandReply::ephemeral(message),
+        }
+    })
+}
+
+// `search <検索語>`
+fn search_command(state: &Arc<SlashCommandState>, command: &SlashCommand) -> CommandReply {
+    let query = arguments(command, "search").to_string();
+    if query.is_empty() {
+        return CommandReply::ephemeral(format!("使い方: `{} search <検索語>`", command.command));
+    }
+    if let Some(reply) = throttled(state, &command.user_id) {
+        return reply;
+    }
+
+    let task_state = state.clone();
+    let channel = command.channel_id.clone();
+    defer(state, command, async move {
+        let state = task_state;
+        let snippets = match state.retriever.retrieve(&channel, "", &query).await {
+            Ok(snippets) => snippets,
+            Err(e) => {
+                info!("過去メッセージの検索に失敗: {}", e);
+                return CommandReply::ephemeral("検索に失敗しました。");
+            }
+        };
+        if snippets.is_empty() {
+            return CommandReply::ephemeral(format!(
+                "「{}」に一致するメッセージはありません。",
+                query
+            ));
+        }
+
+        let mut text = format!("*「{}」の検索結果*", query);
+        for snippet in snippets {
+            let mut preview: String = snippet
+                .message
+                .text
+                .chars()
+                .take(SEARCH_PREVIEW_CHARS)
+                .collect();
+            if preview.len() < snippet.message.text.len() {
+                preview.push('…');
+            }
+            text.push_str(&format!(
+                "\n• <{}|{}> <@{}>: {}",
+                snippet.permalink,
+                snippet.message.ts,
+                snippet.message.sender,
+                preview.replace('\n', " ")
+            ));
+        }
+        CommandReply::ephemeral(text)
+    })
+}
+
+// `summarize [時間 | スレッドのURL]` チャンネルの直近の発言かスレッドを要約する
+//
+// スレッドはコマンドを実行したチャンネルのものに限る
+fn summarize_command(
+    state: &Arc<SlashCommandState>,
+    command: &SlashCommand,
+    arg: Option<&str>,
+) -> CommandReply {
+    let channel = command.channel_id.clone();
+    let default_hours = state.summarizer.options().default_hours;
+    let target = match arg {
+        None => SummaryTarget::Channel {
+            channel: channel.clone(),
+            hours: default_hours,
+        },
+        Some(arg) => match (arg.parse::<u64>(), summarize::parse_permalink(arg)) {
+            (Ok(hours), _) if hours > 0 => SummaryTarget::Channel {
+                channel: channel.clone(),
+                hours,
+            },
+            // 他のチャンネル(ボットだけが読める非公開チャンネルなど)の内容は要約しない
+            (_, Some((thread_channel, _))) if thread_channel != channel => {
+                return CommandReply::ephemeral("要約できるのはこのチャンネルのスレッドだけです。");
+            }
+            (_, Some((channel, thread_ts))) => SummaryTarget::Thread { channel, thread_ts },
+            _ => {
+                return CommandReply::ephemeral(format!(
+                    "使い方: `{0} summarize [時間 | スレッドのURL]` (例: `{0} summarize 12`)",
+                    command.command
+                ));
+            }
+        },
+    };
+    let model =
+        match state
+            .runtime
+            .current()
+            .models
+            .resolve(command.team_id.as_deref(), &channel, None)
+        {
+            Ok(model) => model,
+            Err(e) => return CommandReply::ephemeral(e.to_string()),
+        };
+    if let Some(reply) = throttled(state, &command.user_id) {
+        return reply;
+    }
+
+    let task_state = state.clone();
+    defer(state, command, async move {
+        let state = task_state;
+        let Some(slot) = state.queue.try_enqueue() else {
+            return CommandReply::ephemeral(
+                "ただいま混み合っています。しばらくしてからもう一度お試しください。",
+            );
+        };
+        let _permit = slot.start().await;
+        match state.summarizer.summarize(&target, &model).await {
+            Ok(summary) => CommandReply::ephemeral(summary),
+            Err(e) => {
+                info!("要約に失敗: {}", e);
+                CommandReply::ephemeral(summarize::failure_message(&e))
+            }
+        }
+    })
+}
+
+// `persona` / `persona <名前>` / `persona reset`
+fn persona_command(
+    state: &SlashCommandState,
+    command: &SlashCommand,
+    name: Option<&str>,
+) -> CommandReply {
+    let runtime = state.runtime.current();
+    let personas = &runtime.personas;
+    let channel = &command.channel_id;
+
+    match name {
+        None => {
+            let current = match persona::active_persona(personas, &state.store, channel) {
+                Ok(Some(active)) => {
+                    let source = match active.source {
+                        PersonaSource::Command => "コマンドで設定",
+                        PersonaSource::Channel => "設定ファイルのチャンネル割り当て",
+                        PersonaSource::Default => "既定",
+                    };
+                    format!("現在のペルソナ: `{}` ({})", active.name, source)
+                }
+                Ok(None) => "現在のペルソナ: なし".to_string(),
+                Err(e) => {
+                    info!("ペルソナの取得に失敗: {}", e);
+                    return CommandReply::ephemeral("ペルソナの取得に失敗しました。");
+                }
+            };
+            let list = persona::names(personas)
+                .into_iter()
+                .map(|name| {
+                    let description = &personas.definitions[name].description;
+                    format!("• `{}` {}", name, description)
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            CommandReply::ephemeral(format!("{}\n\n利用できるペルソナ:\n{}", current, list))
+        }
+        Some("reset") => match persona::reset(&state.store, channel) {
+            Ok(()) => CommandReply::public(format!(
+                "<@{}> がこのチャンネルのペルソナを設定ファイルの割り当てに戻しました。",
+                command.user_id
+            )),
+            Err(e) => {
+                info!("ペルソナのリセットに失敗: {}", e);
+                CommandReply::ephemeral("ペルソナのリセットに失敗しました。")
+            }
+        },
+        Some(name) => match persona::assign(personas, &state.store, channel, name) {
+            Ok(()) => CommandReply::public(format!(
+                "<@{}> がこのチャンネルのペルソナを `{}` に切り替えました。",
+                command.user_id, name
+            )),
+            Err(message) => CommandReply::ephemeral(message),
+        },
+    }
+}
+
+// `policy` / `policy <モード> [キーワード...]` / `policy reset`
+async fn policy_command(
+    state: &SlashCommandState,
+    command: &SlashCommand,
+    args: Vec<&str>,
+) -> CommandReply {
+    let runtime = state.runtime.current();
+    let config = &runtime.policy;
+    let channel = &command.channel_id;
+
+    let Some(&first) = args.first() else {
+        return match config.for_channel(&state.store, channel) {
+            Ok(current) => {
+                let source = match current.source {
+                    PolicySource::Command => "コマンドで設定",
+                    PolicySource::Channel => "設定ファイルのチャンネル指定",
+                    PolicySource::Default => "既定",
+                };
+                let keywords = if current.keywords.is_empty() {
+                    String::new()
+                } else {
+                    format!(" キーワード: {}", current.keywords.join(", "))
+                };
+                let modes = RespondMode::ALL
+                    .iter()
+                    .map(|mode| format!("`{}`", mode))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                CommandReply::ephemeral(format!(
+                    "現在の応答モード: `{}` ({}){}\n\n利用できるモード: {}",
+                    current.mode, source, keywords, modes
+                ))
+            }
+            Err(e) => {
+                info!("ポリシーの取得に失敗: {}", e);
+                CommandReply::ephemeral("ポリシーの取得に失敗しました。")
+            }
+        };
+    };
+
+    if !is_policy_admin(state, &command.user_id).await {
+        return CommandReply::ephemeral(
+            "応答モードを変更できるのはワークスペースの管理者と設定ファイルで指定された管理者だけです。",
+        );
+    }
+
+    if first == "reset" {
+        return match policy::reset(&state.store, channel) {
+            Ok(()) => CommandReply::public(format!(
+                "<@{}> がこのチャンネルの応答モードを設定ファイルの指定に戻しました。",
+                command.user_id
+            )),
+            Err(e) => {
+                info!("ポリシーのリセットに失敗: {}", e);
+                CommandReply::ephemeral("応答モードのリセットに失敗しました。")
+            }
+        };
+    }
+
+    let mode: RespondMode = match first.parse() {
+        Ok(mode) => mode,
+        Err(message) => return CommandReply::ephemeral(message),
+    };
+    let keywords: Vec<String> = args[1..].iter().map(|k| k.to_string()).collect();
+    if mode == RespondMode::Keywords && keywords.is_empty() {
+        return CommandReply::ephemeral(
+            "`keywords`モードにはキーワードを1つ以上指定してください。",
+        );
+    }
+
+    match policy::assign(&state.store, channel, mode, &keywords) {
+        Ok(()) => CommandReply::public(format!(
+            "<@{}> がこのチャンネルの応答モードを `{}` に変更しました。",
+            command.user_id, mode
+        )),
+        Err(e) => {
+            info!("ポリシーの保存に失敗: {}", e);
+            CommandReply::ephemeral("応答モードの保存に失敗しました。")
+        }
+    }
+}
+
+// 設定ファイルの管理者か、Slackワークスペースの管理者・オーナーか
+async fn is_policy_admin(state: &SlashCommandState, user: &str) -> bool {
+    if state.runtime.current().policy.is_admin(user) {
+        return true;
+    }
+    match state.slack_api.users_info(user).await {
+        Ok(info) => {
+            info["is_admin"].as_bool() == Some(true) || info["is_owner"].as_bool() == Some(true)
+        }
+        Err(e) => {
+            info!("ユーザー情報の取得に失敗: {}", e);
+            false
+        }
+    }
+}
+
+fn help_text(state: &SlashCommandState, command: &str) -> String {
+    format!(
+        "*使い方*\n\
+         `{0} ask [--public] <質問>` 質問に回答(`--public`でチャンネルに表示)\n\
+         `{0} search <検索語>` 過去のメッセージを検索\n\
+         `{0} summarize [時間]` このチャンネルの直近の発言を要約(既定は{1}時間)\n\
+         `{0} summarize <スレッドのURL>` スレッドを要約\n\
+         `{0} persona` 現在のペルソナと利用できるペルソナを表示\n\
+         `{0} persona <名前>` このチャンネルのペルソナを切り替え\n\
+         `{0} persona reset` 設定ファイルの割り当てに戻す\n\
+         `{0} policy` 現在の応答モードを表示\n\
+         `{0} policy <モード> [キーワード...]` このチャンネルの応答モードを変更(管理者のみ)\n\
+         `{0} policy reset` 設定ファイルの応答モードに戻す(管理者のみ)\n\
+         `{0} help` このヘルプを表示",
+        command,
+        state.summarizer.options().default_hours
+    )
+}
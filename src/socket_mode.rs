@@ -0,0 +1,461 @@
+//! SlackのSocket Mode
+//!
+//! 公開URLを用意できない環境向けに、アプリレベルトークン(`xapp-`)で
+//! `apps.connections.open`を呼んでWebSocketを開き、イベントとスラッシュコマンドを
+//! 受け取る。受け取ったenvelopeはすぐにackし、イベントはHTTPのイベントAPIと同じ
+//! `SlackEventHandler`に渡す。切断されたらバックオフしながら再接続する。
+
+use crate::dedup::DedupCache;
+use crate::event_details::EventDetailsCache;
+use crate::slash_command::{self, SlashCommand, SlashCommandState};
+use anyhow::{Context, anyhow};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+use slack_rs::{Event, MessageClient, SlackEventHandler};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+const CONNECTIONS_OPEN_URL: &str = "https://slack.com/api/apps.connections.open";
+
+// 再接続の待ち時間
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct SocketModeOptions {
+    /// アプリレベルトークン(`connections:write`スコープ)
+    pub app_token: String,
+    /// 指定するとapps.connections.openを呼ばずにこのURLへ接続する(ローカルの検証用)
+    pub url: Option<String>,
+}
+
+pub struct SocketModeClient<H> {
+    http: reqwest::Client,
+    options: SocketModeOptions,
+    handler: H,
+    client: MessageClient,
+    dedup: DedupCache,
+    details: EventDetailsCache,
+    slash_commands: Arc<SlashCommandState>,
+}
+
+impl<H: SlackEventHandler + Send + Sync> SocketModeClient<H> {
+    pub fn new(
+        options: SocketModeOptions,
+        handler: H,
+        client: MessageClient,
+        dedup: DedupCache,
+        details: EventDetailsCache,
+        slash_commands: SlashCommandState,
+    ) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            options,
+            handler,
+            client,
+            dedup,
+            details,
+            slash_commands: Arc::new(slash_commands),
+        }
+    }
+
+    /// `shutdown`が取り消されるまで接続を維持する。切断やエラーのたびに再接続する
+    pub async fn run(&self, shutdown: CancellationToken) -> anyhow::Result<()> {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let result = self.session(&shutdown).await;
+            if shutdown.is_cancelled() {
+                info!("Socket Modeの接続を終了しました");
+                return Ok(());
+            }
+            match result {
+                Ok(connected) => {
+                    info!("Socket Modeの接続が閉じられたため再接続します");
+                    if connected {
+                        backoff = INITIAL_BACKOFF;
+                    }
+                }
+                Err(e) => info!("Socket Modeの接続に失敗: {}", e),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = shutdown.cancelled() => return Ok(()),
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    // WebSocketのURLを取得する
+    async fn open_url(&self) -> anyhow::Result<String> {
+        if let Some(url) = &self.options.url {
+            return Ok(url.clone());
+        }
+        let response: Value = self
+            .http
+            .post(CONNECTIONS_OPEN_URL)
+            .bearer_auth(&self.options.app_token)
+            .send()
+            .await
+            .context("apps.connections.openの呼び出しに失敗しました")?
+            .json()
+            .await
+            .context("apps.connections.openの応答を解析できませんでした")?;
+        if response["ok"].as_bool() != Some(true) {
+            return Err(anyhow!(
+                "apps.connections.openがエラーを返しました: {}",
+                response["error"].as_str().unwrap_or("unknown_error")
+            ));
+        }
+        response["url"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("apps.connections.openの応答にurlがありません"))
+    }
+
+    // 1回分の接続。helloを受け取っていればtrueを返す
+    async fn session(&self, shutdown: &CancellationToken) -> anyhow::Result<bool> {
+        let url = self.open_url().await?;
+        let (mut socket, _) = tokio_tungstenite::connect_async(url.as_str())
+            .await
+            .context("WebSocketに接続できませんでした")?;
+        let mut connected = false;
+
+        loop {
+            let frame = tokio::select! {
+                frame = socket.next() => frame,
+                _ = shutdown.cancelled() => {
+                    socket.close(None).await.ok();
+                    break;
+                }
+            };
+            let Some(frame) = frame else {
+                break;
+            };
+            let text = match frame.context("WebSocketの受信に失敗しました")? {
+                WsMessage::Text(text) => text,
+                WsMessage::Ping(data) => {
+                    socket.send(WsMessage::Pong(data)).await?;
+                    continue;
+                }
+                WsMessage::Close(_) => break,
+                _ => continue,
+            };
+            let envelope: Value = match serde_json::from_str(&text) {
+                Ok(envelope) => envelope,
+                Err(e) => {
+                    info!("Socket Modeのメッセージを解析できません: {}", e);
+                    continue;
+                }
+            };
+
+            match envelope["type"].as_str().unwrap_or_default() {
+                "hello" => {
+                    info!("Socket Modeで接続しました");
+                    connected = true;
+                }
+                "disconnect" => {
+                    info!(
+                        "Slackから切断を要求されました: reason={}",
+                        envelope["reason"].as_str().unwrap_or("-")
+                    );
+                    break;
+                }
+                kind => {
+                    // Slackは3秒以内のackを求めるため、処理より先に返す。
+                    // スラッシュコマンドだけは応答をackに載せる
+                    let Some(envelope_id) = envelope["envelope_id"].as_str() else {
+                        debug!("envelope_idのないメッセージを無視: type={}", kind);
+                        continue;
+                    };
+                    let mut ack = json!({ "envelope_id": envelope_id });
+                    if kind == "slash_commands" {
+                        if let Some(payload) = self.slash_command(&envelope["payload"]).await {
+                            ack["payload"] = payload;
+                        }
+                        socket.send(WsMessage::Text(ack.to_string())).await?;
+                        continue;
+                    }
+                    socket.send(WsMessage::Text(ack.to_string())).await?;
+
+                    if kind == "events_api" {
+                        self.event(&envelope["payload"]).await;
+                    } else {
+                        debug!("未対応のSocket Modeメッセージ: type={}", kind);
+                    }
+                }
+            }
+        }
+        Ok(connected)
+    }
+
+    // events_apiのpayload(イベントAPIのリクエストボディと同じ形)を処理する
+    async fn event(&self, payload: &Value) {
+        if let Some(event_id) = payload["event_id"].as_str()
+            && !self.dedup.first_event(event_id)
+        {
+            info!("重複したイベントを無視: event_id={}", event_id);
+            return;
+        }
+
+        self.details.record(payload);
+        let event: Event = match serde_json::from_value(payload["event"].clone()) {
+            Ok(event) => event,
+            Err(e) => {
+                info!("イベントを解析できません: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = self.handler.handle_event(event, &self.client).await {
+            info!("イベントの処理に失敗: {}", e);
+        }
+    }
+
+    async fn slash_command(&self, payload: &Value) -> Option<Value> {
+        match serde_json::from_value::<SlashCommand>(payload.clone()) {
+            Ok(command) => Some(slash_command::respond(&self.slash_commands, command).await),
+            Err(e) => {
+                info!("スラッシュコマンドを解析できません: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Runtime;
+    use crate::config::RuntimeConfig;
+    use crate::identity::BotIdentity;
+    use crate::llm::LLMClient;
+    use crate::rate_limit::{LimitOptions, RateLimiter, WorkQueue};
+    use crate::reload::RuntimeHandle;
+    use crate::retrieval::{RetrievalOptions, Retriever};
+    use crate::slack_api::SlackApi;
+    use crate::store::MessageStore;
+    use crate::summarize::{Summarizer, SummaryOptions};
+    use slack_rs::Token;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use tokio_tungstenite::WebSocketStream;
+    use tokio_util::task::TaskTracker;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    const MENTION_TS: &str = "1700000000.000200";
+    const THREAD_TS: &str = "1700000000.000100";
+
+    // 受け取ったメンションの(channel, ts)を送る
+    struct RecordingHandler(mpsc::UnboundedSender<(String, String)>);
+
+    #[async_trait::async_trait]
+    impl SlackEventHandler for RecordingHandler {
+        async fn handle_event(
+            &self,
+            event: Event,
+            _client: &MessageClient,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            if let Event::AppMention { channel, ts, .. } = event {
+                self.0.send((channel, ts)).ok();
+            }
+            Ok(())
+        }
+    }
+
+    // helpだけを使うため、外部への接続は発生しない
+    fn slash_command_state() -> SlashCommandState {
+        let store = MessageStore::open(":memory:").unwrap();
+        let slack_api = SlackApi::new("xoxb-test".to_string());
+        let llm_client = LLMClient::new(
+            "http://127.0.0.1:9".to_string(),
+            "test".to_string(),
+            "test".to_string(),
+        );
+        let limits = LimitOptions::default();
+        let identity = BotIdentity {
+            user_id: "UBOT".to_string(),
+            bot_id: None,
+            team_id: None,
+        };
+        SlashCommandState {
+            signing_secret: String::new(),
+            store: store.clone(),
+            slack_api: slack_api.clone(),
+            runtime: RuntimeHandle::new(Runtime::new(&RuntimeConfig::default())),
+            llm_client: llm_client.clone(),
+            retriever: Retriever::new(
+                store,
+                slack_api.clone(),
+                None,
+                identity.user_id.clone(),
+                RetrievalOptions::default(),
+            ),
+            queue: WorkQueue::new(&limits),
+            users: RateLimiter::new(limits.user),
+            summarizer: Summarizer::new(slack_api, llm_client, identity, SummaryOptions::default()),
+            http: reqwest::Client::new(),
+            tasks: TaskTracker::new(),
+        }
+    }
+
+    fn mention_envelope(envelope_id: &str, event_id: &str) -> Value {
+        json!({
+            "envelope_id": envelope_id,
+            "type": "events_api",
+            "accepts_response_payload": false,
+            "payload": {
+                "type": "event_callback",
+                "team_id": "T1",
+                "event_id": event_id,
+                "event": {
+                    "type": "app_mention",
+                    "user": "U1",
+                    "text": "<@UBOT> こんにちは",
+                    "ts": MENTION_TS,
+                    "thread_ts": THREAD_TS,
+                    "channel": "C1",
+                    "event_ts": MENTION_TS,
+                    "team": "T1",
+                },
+            },
+        })
+    }
+
+    async fn accept(listener: &TcpListener) -> WebSocketStream<TcpStream> {
+        let (stream, _) = tokio::time::timeout(TIMEOUT, listener.accept())
+            .await
+            .expect("接続されませんでした")
+            .unwrap();
+        tokio_tungstenite::accept_async(stream).await.unwrap()
+    }
+
+    async fn send(socket: &mut WebSocketStream<TcpStream>, value: Value) {
+        socket
+            .send(WsMessage::Text(value.to_string()))
+            .await
+            .unwrap();
+    }
+
+    // 次のテキストフレームをJSONとして受け取る
+    async fn receive(socket: &mut WebSocketStream<TcpStream>) -> Value {
+        loop {
+            let frame = tokio::time::timeout(TIMEOUT, socket.next())
+                .await
+                .expect("ackが届きませんでした")
+                .expect("接続が閉じられました")
+                .unwrap();
+            if let WsMessage::Text(text) = frame {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn acks_envelopes_and_reconnects_after_disconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let details = EventDetailsCache::default();
+        let client = SocketModeClient::new(
+            SocketModeOptions {
+                app_token: String::new(),
+                url: Some(url),
+            },
+            RecordingHandler(events_tx),
+            MessageClient::new(Token::new("xoxb-test".to_string())),
+            DedupCache::default(),
+            details.clone(),
+            slash_command_state(),
+        );
+        let shutdown = CancellationToken::new();
+
+        // Slack側の振る舞い。終了時(失敗時を含む)にクライアントを止める
+        let server_shutdown = shutdown.clone();
+        let server = tokio::spawn(async move {
+            let _stop_client = server_shutdown.drop_guard();
+
+            let mut socket = accept(&listener).await;
+            send(
+                &mut socket,
+                json!({ "type": "hello", "num_connections": 1 }),
+            )
+            .await;
+
+            // イベントはackしてからハンドラに渡し、ペイロードのフィールドを記録する
+            send(&mut socket, mention_envelope("env-1", "Ev1")).await;
+            assert_eq!(
+                receive(&mut socket).await,
+                json!({ "envelope_id": "env-1" })
+            );
+            let mention = tokio::time::timeout(TIMEOUT, events.recv())
+                .await
+                .expect("イベントが渡されませんでした");
+            assert_eq!(mention, Some(("C1".to_string(), MENTION_TS.to_string())));
+            let recorded = details.get("C1", MENTION_TS);
+            assert_eq!(recorded.thread_ts.as_deref(), Some(THREAD_TS));
+            assert_eq!(recorded.user.as_deref(), Some("U1"));
+
+            // 再送されたイベントもackするが、ハンドラには渡さない
+            send(&mut socket, mention_envelope("env-2", "Ev1")).await;
+            assert_eq!(
+                receive(&mut socket).await,
+                json!({ "envelope_id": "env-2" })
+            );
+
+            // スラッシュコマンドは応答をackに載せる
+            send(
+                &mut socket,
+                json!({
+                    "envelope_id": "env-3",
+                    "type": "slash_commands",
+                    "accepts_response_payload": true,
+                    "payload": {
+                        "command": "/stockmind",
+                        "text": "help",
+                        "channel_id": "C1",
+                        "user_id": "U1",
+                        "team_id": "T1",
+                        "response_url": "https://hooks.slack.com/commands/T1/1/x",
+                    },
+                }),
+            )
+            .await;
+            let ack = receive(&mut socket).await;
+            assert_eq!(ack["envelope_id"], "env-3");
+            assert_eq!(ack["payload"]["response_type"], "ephemeral");
+            assert!(
+                ack["payload"]["text"]
+                    .as_str()
+                    .unwrap()
+                    .contains("/stockmind ask")
+            );
+            assert!(events.try_recv().is_err());
+
+            // 切断を要求したら接続し直してくる
+            send(
+                &mut socket,
+                json!({ "type": "disconnect", "reason": "refresh_requested" }),
+            )
+            .await;
+            let mut socket = accept(&listener).await;
+            send(
+                &mut socket,
+                json!({ "type": "hello", "num_connections": 1 }),
+            )
+            .await;
+            send(&mut socket, mention_envelope("env-4", "Ev2")).await;
+            assert_eq!(
+                receive(&mut socket).await,
+                json!({ "envelope_id": "env-4" })
+            );
+        });
+
+        tokio::time::timeout(Duration::from_secs(30), client.run(shutdown))
+            .await
+            .expect("クライアントが終了しませんでした")
+            .unwrap();
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,632 @@
+//! 受信したSlackメッセージの永続化
+//!
+//! 検索や要約の土台として、受信した全メッセージをSQLiteに保存する。
+//! `(channel, ts)`を主キーにしたupsertなので、Slackの再送で重複は生じない。
+
+use anyhow::Context;
+use rusqlite::{Connection, OptionalExtension, params};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// 保存対象のメッセージ
+#[derive(Debug, Clone)]
+pub struct StoredMessage {
+    pub channel: String,
+    pub ts: String,
+    pub thread_ts: Option<String>,
+    pub team_id: Option<String>,
+    pub sender: String,
+    pub text: String,
+}
+
+// ダイジェストの実行記録
+#[derive(Debug, Clone)]
+pub struct DigestRun {
+    pub started_at: i64,
+    /// 投稿済みならそのメッセージのts
+    pub posted_ts: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct MessageStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl MessageStore {
+    /// データベースを開き、必要ならスキーマを作成する
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let conn = Connection::open(path)
+            .with_context(|| format!("データベースを開けません: {}", path.display()))?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS messages (
+                 channel     TEXT NOT NULL,
+                 ts          TEXT NOT NULL,
+                 thread_ts   TEXT,
+                 team_id     TEXT,
+                 sender      TEXT NOT NULL,
+                 text        TEXT NOT NULL,
+                 received_at INTEGER NOT NULL,
+                 PRIMARY KEY (channel, ts)
+             );
+             CREATE INDEX IF NOT EXISTS messages_thread
+                 ON messages (channel, thread_ts);
+             CREATE TABLE IF NOT EXISTS channel_settings (
+                 channel    TEXT NOT NULL,
+                 key        TEXT NOT NULL,
+                 value      TEXT NOT NULL,
+                 updated_at INTEGER NOT NULL,
+                 PRIMARY KEY (channel, key)
+             );
+             CREATE TABLE IF NOT EXISTS digest_runs (
+                 name         TEXT NOT NULL,
+                 scheduled_at INTEGER NOT NULL,
+                 started_at   INTEGER NOT NULL,
+                 posted_ts    TEXT,
+                 PRIMARY KEY (name, scheduled_at)
+             );",
+        )
+        .context("スキーマの作成に失敗しました")?;
+
+        // 全文検索用のインデックス。日本語は単語境界がないためtrigramで分割する
+        let has_fts: bool = conn
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE name = 'messages_fts')",
+                [],
+                |row| row.get(0),
+            )
+            .context("全文検索インデックスの確認に失敗しました")?;
+        conn.execute_batch(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5 (
+                 text, content = 'messages', content_rowid = 'rowid', tokenize = 'trigram'
+             );
+             CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+                 INSERT INTO messages_fts (rowid, text) VALUES (new.rowid, new.text);
+             END;
+             CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+                 INSERT INTO messages_fts (messages_fts, rowid, text)
+                     VALUES ('delete', old.rowid, old.text);
+             END;
+             CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF text ON messages BEGIN
+                 INSERT INTO messages_fts (messages_fts, rowid, text)
+                     VALUES ('delete', old.rowid, old.text);
+                 INSERT INTO messages_fts (rowid, text) VALUES (new.rowid, new.text);
+             END;",
+        )
+        .context("全文検索インデックスの作成に失敗しました")?;
+        if !has_fts {
+            // 既存のメッセージをインデックスに取り込む
+            conn.execute_batch("INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');")
+                .context("全文検索インデックスの再構築に失敗しました")?;
+        }
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        // 書き込み中のパニックでロックが汚染されても接続自体は使える
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// データベースを読み出せるか確認する
+    pub fn check(&self) -> anyhow::Result<()> {
+        self.conn()
+            .query_row("SELECT 1 FROM messages LIMIT 1", [], |_| Ok(()))
+            .optional()
+            .context("メッセージストアを読み出せません")?;
+        Ok(())
+    }
+
+    /// メッセージを保存する。同じ`(channel, ts)`が既にあれば内容を更新する
+    pub fn upsert(&self, message: &StoredMessage) -> anyhow::Result<()> {
+        self.conn()
+            .execute(
+                "INSERT INTO messages (channel, ts, thread_ts, team_id, sender, text, received_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                 ON CONFLICT (channel, ts) DO UPDATE SET
+                     thread_ts = COALESCE(excluded.thread_ts, messages.thread_ts),
+                     team_id   = COALESCE(excluded.team_id, messages.team_id),
+                     sender    = excluded.sender,
+                     text      = excluded.text",
+                params![
+                    message.channel,
+                    message.ts,
+                    message.thread_ts,
+                    message.team_id,
+                    message.sender,
+                    message.text,
+                    now_unix(),
+                ],
+            )
+            .context("メッセージの保存に失敗しました")?;
+        Ok(())
+    }
+
+    /// スレッドが判明したメッセージにthread_tsを記録する
+    pub fn set_thread_ts(&self, channel: &str, ts: &str, thread_ts: &str) -> anyhow::Result<()> {
+        self.conn()
+            .execute(
+                "UPDATE messages SET thread_ts = ?3 WHERE channel = ?1 AND ts = ?2",
+                params![channel, ts, thread_ts],
+            )
+            .context("thread_tsの更新に失敗しました")?;
+        Ok(())
+    }
+
+    /// スラッシュコマンドなどで実行中に変更されたチャンネルごとの設定を取得する
+    pub fn channel_setting(&self, channel: &str, key: &str) -> anyhow::Result<Option<String>> {
+        self.conn()
+            .query_row(
+                "SELECT value FROM channel_settings WHERE channel = ?1 AND key = ?2",
+                params![channel, key],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("チャンネル設定の取得に失敗しました")
+    }
+
+    pub fn set_channel_setting(&self, channel: &str, key: &str, value: &str) -> anyhow::Result<()> {
+        self.conn()
+            .execute(
+                "INSERT INTO channel_settings (channel, key, value, updated_at)
+                 VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (channel, key) DO UPDATE SET
+                     value      = excluded.value,
+                     updated_at = excluded.updated_at",
+                params![channel, key, value, now_unix()],
+            )
+            .context("チャンネル設定の保存に失敗しました")?;
+        Ok(())
+    }
+
+    pub fn delete_channel_setting(&self, channel: &str, key: &str) -> anyhow::Result<()> {
+        self.conn()
+            .execute(
+                "DELETE FROM channel_settings WHERE channel = ?1 AND key = ?2",
+                params![channel, key],
+            )
+            .context("チャンネル設定の削除に失敗しました")?;
+        Ok(())
+    }
+
+    pub fn get(&self, channel: &str, ts: &str) -> anyhow::Result<Option<StoredMessage>> {
+        self.conn()
+            .query_row(
+                "SELECT channel, ts, thread_ts, team_id, sender, text
+                 FROM messages WHERE channel = ?1 AND ts = ?2",
+                params![channel, ts],
+                read_message,
+            )
+            .optional()
+            .context("メッセージの取得に失敗しました")
+    }
+
+    /// 全文検索で関連するメッセージを関連度順に取得する
+    ///
+    /// `channel`を指定するとそのチャンネルに限定する。`exclude_sender`の発言
+    /// (ボット自身の返信など)と`exclude_ts`のメッセージは結果に含めない。
+    /// trigramで検索できない2文字の語(「会議」など)は部分一致で探し、
+    /// 全文検索の結果の後ろに加える。
+    pub fn search(
+        &self,
+        text: &str,
+        channel: Option<&str>,
+        exclude_sender: &str,
+        exclude_ts: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<StoredMessage>> {
+        let terms = search_terms(text);
+        let mut messages = match &terms.fts {
+            Some(query) => self.search_fts(query, channel, exclude_sender, exclude_ts, limit)?,
+            None => Vec::new(),
+        };
+        if terms.short.is_empty() || messages.len() >= limit {
+            return Ok(messages);
+        }
+
+        let similar = self.search_like(&terms.short, channel, exclude_sender, exclude_ts, limit)?;
+        for message in similar {
+            if messages.len() >= limit {
+                break;
+            }
+            if !messages
+                .iter()
+                .any(|m| m.channel == message.channel && m.ts == message.ts)
+            {
+                messages.push(message);
+            }
+        }
+        Ok(messages)
+    }
+
+    // FTS5の検索式で探し、bm25の順に返す
+    fn search_fts(
+        &self,
+        query: &str,
+        channel: Option<&str>,
+        exclude_sender: &str,
+        exclude_ts: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<StoredMessage>> {
+        let conn = self.conn();
+        let mut stmt = conn
+            .prepare(
+                "SELECT m.channel, m.ts, m.thread_ts, m.team_id, m.sender, m.text
+                 FROM messages_fts
+                 JOIN messages m ON m.rowid = messages_fts.rowid
+                 WHERE messages_fts MATCH ?1
+                   AND (?2 IS NULL OR m.channel = ?2)
+                   AND m.sender != ?3
+                   AND m.ts != ?4
+                 ORDER BY bm25(messages_fts)
+                 LIMIT ?5",
+            )
+            .context("検索クエリの準備に失敗しました")?;
+        let rows = stmt
+            .query_map(
+                params![query, channel, exclude_sender, exclude_ts, limit as i64],
+                read_message,
+            )
+            .context("メッセージの検索に失敗しました")?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .context("検索結果の読み込みに失敗しました")
+    }
+
+    // いずれかの語を含むメッセージを、一致した語が多く新しいものから返す
+    fn search_like(
+        &self,
+        words: &[String],
+        channel: Option<&str>,
+        exclude_sender: &str,
+        exclude_ts: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<StoredMessage>> {
+        // 語は英数字のみなのでLIKEのワイルドカードを含まない
+        let words = serde_json::to_string(words).context("検索語を変換できません")?;
+        let conn = self.conn();
+        let mut stmt = conn
+            .prepare(
+                "SELECT channel, ts, thread_ts, team_id, sender, text FROM (
+                     SELECT m.*, (
+                         SELECT COUNT(*) FROM json_each(?1) w
+                         WHERE m.text LIKE '%' || w.value || '%'
+                     ) AS hits
+                     FROM messages m
+                     WHERE (?2 IS NULL OR m.channel = ?2)
+                       AND m.sender != ?3
+                       AND m.ts != ?4
+                 )
+                 WHERE hits > 0
+                 ORDER BY hits DESC, CAST(ts AS REAL) DESC
+                 LIMIT ?5",
+            )
+            .context("検索クエリの準備に失敗しました")?;
+        let rows = stmt
+            .query_map(
+                params![words, channel, exclude_sender, exclude_ts, limit as i64],
+                read_message,
+            )
+            .context("メッセージの検索に失敗しました")?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .context("検索結果の読み込みに失敗しました")
+    }
+
+    /// チャンネルの`[since, until)`(Unix秒)のメッセージを古い順に取得する
+    ///
+    /// 件数が`limit`を超える場合は新しいものを優先する。
+    pub fn messages_between(
+        &self,
+        channel: &str,
+        since: i64,
+        until: i64,
+        limit: usize,
+    ) -> anyhow::Result<Vec<StoredMessage>> {
+        let conn = self.conn();
+        let mut stmt = conn
+            .prepare(
+                "SELECT channel, ts, thread_ts, team_id, sender, text FROM (
+                     SELECT * FROM messages
+                     WHERE channel = ?1 AND CAST(ts AS REAL) >= ?2 AND CAST(ts AS REAL) < ?3
+                     ORDER BY CAST(ts AS REAL) DESC
+                     LIMIT ?4
+                 ) ORDER BY CAST(ts AS REAL)",
+            )
+            .context("クエリの準備に失敗しました")?;
+        let rows = stmt
+            .query_map(params![channel, since, until, limit as i64], read_message)
+            .context("メッセージの取得に失敗しました")?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .context("メッセージの読み込みに失敗しました")
+    }
+
+    /// ダイジェストの実行を記録する。既に記録があればfalseを返す
+    pub fn claim_digest_run(&self, name: &str, scheduled_at: i64) -> anyhow::Result<bool> {
+        let inserted = self
+            .conn()
+            .execute(
+                "INSERT INTO digest_runs (name, scheduled_at, started_at) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (name, scheduled_at) DO NOTHING",
+                params![name, scheduled_at, now_unix()],
+            )
+            .context("ダイジェストの実行記録に失敗しました")?;
+        Ok(inserted > 0)
+    }
+
+    pub fn digest_run(&self, name: &str, scheduled_at: i64) -> anyhow::Result<Option<DigestRun>> {
+        self.conn()
+            .query_row(
+                "SELECT started_at, posted_ts FROM digest_runs
+                 WHERE name = ?1 AND scheduled_at = ?2",
+                params![name, scheduled_at],
+                |row| {
+                    Ok(DigestRun {
+                        started_at: row.get(0)?,
+                        posted_ts: row.get(1)?,
+                    })
+                },
+            )
+            .optional()
+            .context("ダイジェストの実行記録の取得に失敗しました")
+    }
+
+    /// 最後に実行したダイジェストの予定時刻
+    pub fn last_digest_run(&self, name: &str) -> anyhow::Result<Option<i64>> {
+        self.conn()
+            .query_row(
+                "SELECT MAX(scheduled_at) FROM digest_runs WHERE name = ?1",
+                params![name],
+                |row| row.get(0),
+            )
+            .context("ダイジェストの実行記録の取得に失敗しました")
+    }
+
+    /// `before`より前に投稿を終えたダイジェストの予定時刻
+    pub fn previous_digest_run(&self, name: &str, before: i64) -> anyhow::Result<Option<i64>> {
+        self.conn()
+            .query_row(
+                "SELECT MAX(scheduled_at) FROM digest_runs
+                 WHERE name = ?1 AND scheduled_at < ?2 AND posted_ts IS NOT NULL",
+                params![name, before],
+                |row| row.get(0),
+            )
+            .context("ダイジェストの実行記録の取得に失敗しました")
+    }
+
+    pub fn finish_digest_run(
+        &self,
+        name: &str,
+        scheduled_at: i64,
+        posted_ts: &str,
+    ) -> anyhow::Result<()> {
+        self.conn()
+            .execute(
+                "UPDATE digest_runs SET posted_ts = ?3 WHERE name = ?1 AND scheduled_at = ?2",
+                params![name, scheduled_at, posted_ts],
+            )
+            .context("ダイジェストの実行記録の更新に失敗しました")?;
+        Ok(())
+    }
+}
+
+// SELECT channel, ts, thread_ts, team_id, sender, text の行を読み込む
+fn read_message(row: &rusqlite::Row<'_>) -> rusqlite::Result<StoredMessage> {
+    Ok(StoredMessage {
+        channel: row.get(0)?,
+        ts: row.get(1)?,
+        thread_ts: row.get(2)?,
+        team_id: row.get(3)?,
+        sender: row.get(4)?,
+        text: row.get(5)?,
+    })
+}
+
+// 自由文から組み立てた検索条件
+#[derive(Debug)]
+struct SearchTerms {
+    /// 3文字以上の語から作ったFTS5の検索式
+    fts: Option<String>,
+    /// trigramでは検索できない2文字の語
+    short: Vec<String>,
+}
+
+/// 自由文から検索条件を組み立てる
+///
+/// trigramトークナイザに合わせて3文字ずつの断片をORで繋ぎ、bm25で
+/// 多くの断片に一致したメッセージほど上位に来るようにする。2文字の語は
+/// 断片を作れないため部分一致用に分ける(1文字の語は一致が多すぎるため使わない)。
+/// Slackのメンションやリンク(`<...>`)は除外する。
+fn search_terms(text: &str) -> SearchTerms {
+    const MAX_TERMS: usize = 32;
+
+    let mut plain = String::new();
+    let mut in_link = false;
+    for c in text.chars() {
+        match c {
+            '<' => in_link = true,
+            '>' => in_link = false,
+            _ if !in_link => plain.push(c),
+            _ => {}
+        }
+    }
+
+    let mut terms: Vec<String> = Vec::new();
+    let mut short: Vec<String> = Vec::new();
+    for word in plain.split(|c: char| !c.is_alphanumeric()) {
+        let chars: Vec<char> = word.chars().collect();
+        if chars.len() == 2 {
+            let word = word.to_lowercase();
+            if !short.contains(&word) && short.len() < MAX_TERMS {
+                short.push(word);
+            }
+            continue;
+        }
+        for window in chars.windows(3) {
+            let term: String = window.iter().collect::<String>().to_lowercase();
+            if !terms.contains(&term) {
+                terms.push(term);
+            }
+        }
+    }
+
+    let fts = (!terms.is_empty()).then(|| {
+        terms
+            .iter()
+            .take(MAX_TERMS)
+            .map(|t| format!("\"{}\"", t.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(" OR ")
+    });
+    SearchTerms { fts, short }
+}
+
+fn now_unix() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(channel: &str, ts: &str, sender: &str, text: &str) -> StoredMessage {
+        StoredMessage {
+            channel: channel.to_string(),
+            ts: ts.to_string(),
+            thread_ts: None,
+            team_id: None,
+            sender: sender.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    fn store_with(messages: &[StoredMessage]) -> MessageStore {
+        let store = MessageStore::open(":memory:").unwrap();
+        for message in messages {
+            store.upsert(message).unwrap();
+        }
+        store
+    }
+
+    fn timestamps(messages: &[StoredMessage]) -> Vec<&str> {
+        messages.iter().map(|m| m.ts.as_str()).collect()
+    }
+
+    #[test]
+    fn upsert_is_idempotent_for_the_same_channel_and_ts() {
+        let mut original = message("C1", "1.0", "U1", "デプロイしました");
+        original.thread_ts = Some("0.5".to_string());
+        let store = store_with(&[original.clone()]);
+
+        // Slackの再送ではthread_tsが欠けることがある
+        let resent = message("C1", "1.0", "U1", "デプロイしました");
+        store.upsert(&resent).unwrap();
+        store.upsert(&resent).unwrap();
+
+        let count: i64 = store
+            .conn()
+            .query_row("SELECT COUNT(*) FROM messages", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+        let stored = store.get("C1", "1.0").unwrap().unwrap();
+        assert_eq!(stored.thread_ts.as_deref(), Some("0.5"));
+        assert_eq!(stored.text, original.text);
+        let found = store.search("デプロイ", None, "UBOT", "", 10).unwrap();
+        assert_eq!(timestamps(&found), ["1.0"]);
+
+        // 同じチャンネルでもtsが異なれば別のメッセージになる
+        store.upsert(&message("C2", "1.0", "U1", "別件")).unwrap();
+        store.upsert(&message("C1", "2.0", "U1", "別件")).unwrap();
+        let count: i64 = store
+            .conn()
+            .query_row("SELECT COUNT(*) FROM messages", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn search_terms_splits_trigrams_and_short_words() {
+        let terms = search_terms("Deploy");
+        assert_eq!(
+            terms.fts.as_deref(),
+            Some("\"dep\" OR \"epl\" OR \"plo\" OR \"loy\"")
+        );
+        assert!(terms.short.is_empty());
+
+        // 2文字の語は部分一致用に分け、1文字の語は使わない
+        let terms = search_terms("会議 と 予定 会議");
+        assert_eq!(terms.fts, None);
+        assert_eq!(terms.short, ["会議", "予定"]);
+
+        let terms = search_terms("デプロイ の 会議");
+        assert_eq!(terms.fts.as_deref(), Some("\"デプロ\" OR \"プロイ\""));
+        assert_eq!(terms.short, ["会議"]);
+    }
+
+    #[test]
+    fn search_terms_skips_mentions_and_links() {
+        let terms = search_terms("<@U123ABC> <https://example.com|link> OK");
+        assert_eq!(terms.fts, None);
+        assert_eq!(terms.short, ["ok"]);
+    }
+
+    #[test]
+    fn search_finds_trigram_matches() {
+        let store = store_with(&[
+            message("C1", "1.0", "U1", "本番環境にデプロイしました"),
+            message("C1", "2.0", "U1", "昼ご飯の話"),
+        ]);
+        let found = store.search("デプロイ", None, "UBOT", "", 10).unwrap();
+        assert_eq!(timestamps(&found), ["1.0"]);
+    }
+
+    #[test]
+    fn search_falls_back_to_like_for_two_character_words() {
+        let store = store_with(&[
+            message("C1", "1.0", "U1", "明日の会議は10時から"),
+            message("C1", "2.0", "U1", "会議室を予約しました"),
+            message("C1", "3.0", "U1", "昼ご飯の話"),
+        ]);
+        let found = store.search("会議", None, "UBOT", "", 10).unwrap();
+        // 部分一致は新しいものから並ぶ
+        assert_eq!(timestamps(&found), ["2.0", "1.0"]);
+    }
+
+    #[test]
+    fn search_appends_like_matches_after_full_text_matches() {
+        let store = store_with(&[
+            message("C1", "1.0", "U1", "デプロイの手順"),
+            message("C1", "2.0", "U1", "会議でデプロイを決めた"),
+            message("C1", "3.0", "U1", "会議の議事録"),
+        ]);
+        let found = store.search("デプロイ 会議", None, "UBOT", "", 10).unwrap();
+        assert_eq!(found.len(), 3);
+        assert_eq!(timestamps(&found[2..]), ["3.0"]);
+
+        let found = store.search("デプロイ 会議", None, "UBOT", "", 2).unwrap();
+        assert_eq!(found.len(), 2);
+        assert!(!timestamps(&found).contains(&"3.0"));
+    }
+
+    #[test]
+    fn search_applies_channel_and_exclusions() {
+        let store = store_with(&[
+            message("C1", "1.0", "U1", "デプロイしました"),
+            message("C2", "2.0", "U1", "デプロイしました"),
+            message("C1", "3.0", "UBOT", "デプロイについての返信"),
+            message("C1", "4.0", "U1", "デプロイはいつ?"),
+        ]);
+        let found = store
+            .search("デプロイ", Some("C1"), "UBOT", "4.0", 10)
+            .unwrap();
+        assert_eq!(timestamps(&found), ["1.0"]);
+
+        let found = store.search("会議", Some("C1"), "UBOT", "", 10).unwrap();
+        assert!(found.is_empty());
+    }
+}
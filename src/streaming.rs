@@ -0,0 +1,125 @@
+//! LLMの応答を逐次Slackに反映する返信モード
+//!
+//! 先にプレースホルダーを投稿し、トークンが届くたびに`chat.update`で
+//! 書き換える。Slackのレート制限に収まるよう更新間隔を間引く。
+
+use crate::slack_api::SlackApi;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::info;
+
+const PLACEHOLDER: &str = "考え中です…";
+// 生成途中であることを示す末尾の記号
+const TYPING_SUFFIX: &str = " ▍";
+// chat.updateが制限された場合の最大更新間隔
+const MAX_UPDATE_INTERVAL: Duration = Duration::from_secs(10);
+
+// 設定ファイルでの指定
+//
+// ```toml
+// [streaming]
+// enabled = true
+// update_interval_ms = 1500
+// ```
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamingOptions {
+    pub enabled: bool,
+    /// chat.updateの最小間隔(ミリ秒)
+    pub update_interval_ms: u64,
+}
+
+impl Default for StreamingOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            update_interval_ms: 1500,
+        }
+    }
+}
+
+impl StreamingOptions {
+    pub fn update_interval(&self) -> Duration {
+        Duration::from_millis(self.update_interval_ms)
+    }
+}
+
+#[derive(Clone)]
+pub struct StreamingReplier {
+    slack_api: SlackApi,
+    options: StreamingOptions,
+}
+
+impl StreamingReplier {
+    pub fn new(slack_api: SlackApi, options: StreamingOptions) -> Self {
+        Self { slack_api, options }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.options.enabled
+    }
+
+    /// スレッドにプレースホルダーを投稿する
+    pub async fn start(&self, channel: &str, thread_ts: &str) -> anyhow::Result<ProgressiveReply> {
+        let ts = self
+            .slack_api
+            .chat_post_message(channel, Some(thread_ts), PLACEHOLDER)
+            .await?;
+        Ok(ProgressiveReply {
+            slack_api: self.slack_api.clone(),
+            channel: channel.to_string(),
+            ts,
+            update_interval: self.options.update_interval(),
+        })
+    }
+}
+
+// 投稿済みのプレースホルダー
+pub struct ProgressiveReply {
+    slack_api: SlackApi,
+    channel: String,
+    ts: String,
+    update_interval: Duration,
+}
+
+impl ProgressiveReply {
+    /// 届いたテキスト片を蓄積し、更新間隔ごとにメッセージを書き換える
+    ///
+    /// 送信側が閉じられるまで続ける。
+    pub async fn follow(&self, mut rx: mpsc::UnboundedReceiver<String>) {
+        let mut text = String::new();
+        let mut interval = self.update_interval;
+        let mut last_update = Instant::now();
+
+        while let Some(delta) = rx.recv().await {
+            text.push_str(&delta);
+            if last_update.elapsed() < interval {
+                continue;
+            }
+
+            let partial = format!("{}{}", text, TYPING_SUFFIX);
+            match self
+                .slack_api
+                .chat_update(&self.channel, &self.ts, &partial)
+                .await
+            {
+                Ok(()) => interval = self.update_interval,
+                Err(e) => {
+                    // 制限された場合は間隔を広げて次の更新まで待つ
+                    interval = (interval * 2).min(MAX_UPDATE_INTERVAL);
+                    info!("途中経過の更新に失敗: {} (次の更新まで{:?})", e, interval);
+                }
+            }
+            last_update = Instant::now();
+        }
+    }
+
+    /// 最終的な内容でメッセージを書き換える
+    pub async fn finish(&self, text: &str) -> anyhow::Result<()> {
+        self.slack_api
+            .chat_update(&self.channel, &self.ts, text)
+            .await
+    }
+}
@@ -0,0 +1,453 @@
+//! スレッドとチャンネルの要約
+//!
+//! `conversations.replies`/`conversations.history`で会話を取得し(チャンネルでは
+//! 期間内に返信があったスレッドの返信も含める)、長い会話は
+//! 一定のトークン数ごとに区切ってそれぞれからメモを抽出(map)してから、
+//! メモをまとめて決定事項・対応事項・未解決の論点に整理する(reduce)。
+
+use crate::conversation::approx_tokens;
+use crate::identity::BotIdentity;
+use crate::llm::{ChatMessage, LLMClient, LLMOptions, ResponseFormat};
+use crate::slack_api::{SlackApi, SlackMessage};
+use serde::Deserialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+// メンションで要約を依頼するときの書き出し
+const TRIGGER_WORDS: &[&str] = &["要約", "まとめて", "summarize", "summary"];
+
+// メモをまとめ直す最大の段数
+const MAX_REDUCE_DEPTH: usize = 3;
+
+const MAP_PROMPT: &str = "以下はSlackの会話ログの一部です。\
+     決定事項、対応事項(担当者や期限があれば含める)、未解決の論点、その他の重要な話題を\
+     日本語の箇条書きで漏れなく抽出してください。ログにないことは書かないでください。";
+
+const REDUCE_PROMPT: &str = "以下はSlackの会話ログ、または会話ログから抽出したメモです。\
+     内容を日本語で要約し、次のJSONだけを出力してください。該当がない項目は空の配列にしてください。\n\
+     {\"overview\": \"全体の概要(2〜3文)\", \"decisions\": [\"決定事項\"], \
+     \"action_items\": [\"対応事項(担当者・期限があれば含める)\"], \"open_questions\": [\"未解決の論点\"]}";
+
+// 設定ファイルでの指定
+//
+// ```toml
+// [summary]
+// chunk_tokens = 3000
+// max_messages = 1000
+// default_hours = 24
+// ```
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SummaryOptions {
+    /// 1回のLLM呼び出しに渡す会話のおおよその最大トークン数
+    pub chunk_tokens: usize,
+    /// 要約の対象にする最大メッセージ数
+    pub max_messages: usize,
+    /// チャンネルを要約するときの既定の期間(時間)
+    pub default_hours: u64,
+}
+
+impl Default for SummaryOptions {
+    fn default() -> Self {
+        Self {
+            chunk_tokens: 3000,
+            max_messages: 1000,
+            default_hours: 24,
+        }
+    }
+}
+
+// 要約の対象
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SummaryTarget {
+    Thread { channel: String, thread_ts: String },
+    Channel { channel: String, hours: u64 },
+}
+
+// LLMが返す構造化された要約
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Summary {
+    overview: String,
+    decisions: Vec<String>,
+    action_items: Vec<String>,
+    open_questions: Vec<String>,
+}
+
+impl Summary {
+    fn render(&self, title: &str) -> String {
+        let mut text = format!("*{}*\n{}", title, self.overview.trim());
+        for (heading, items) in [
+            ("決定事項", &self.decisions),
+            ("対応事項", &self.action_items),
+            ("未解決の論点", &self.open_questions),
+        ] {
+            text.push_str(&format!("\n\n*{}*", heading));
+            if items.is_empty() {
+                text.push_str("\nなし");
+            }
+            for item in items {
+                text.push_str(&format!("\n• {}", item.trim()));
+            }
+        }
+        text
+    }
+}
+
+#[derive(Clone)]
+pub struct Summarizer {
+    slack_api: SlackApi,
+    llm_client: LLMClient,
+    identity: BotIdentity,
+    options: SummaryOptions,
+}
+
+impl Summarizer {
+    pub fn new(
+        slack_api: SlackApi,
+        llm_client: LLMClient,
+        identity: BotIdentity,
+        options: SummaryOptions,
+    ) -> Self {
+        Self {
+            slack_api,
+            llm_client,
+            identity,
+            options,
+        }
+    }
+
+    pub fn options(&self) -> &SummaryOptions {
+        &self.options
+    }
+
+    /// メンションされたメッセージから要約の対象を決める
+    ///
+    /// スレッド内で依頼されたらそのスレッド、チャンネルで依頼されたら直近`hours`時間のチャンネル。
+    pub async fn target_for(
+        &self,
+        channel: &str,
+        ts: &str,
+        hours: Option<u64>,
+    ) -> anyhow::Result<SummaryTarget> {
+        let head = self
+            .slack_api
+            .conversations_replies_head(channel, ts)
+            .await?;
+        let target = match head {
+            Some(parent) if parent.thread_ts.is_some() => SummaryTarget::Thread {
+                channel: channel.to_string(),
+                thread_ts: parent.ts,
+            },
+            _ => SummaryTarget::Channel {
+                channel: channel.to_string(),
+                hours: hours.unwrap_or(self.options.default_hours),
+            },
+        };
+        Ok(target)
+    }
+
+    /// 対象の会話を要約し、Slackに投稿できる形式で返す
+    pub async fn summarize(&self, target: &SummaryTarget, model: &str) -> anyhow::Result<String> {
+        let (title, messages) = match target {
+            SummaryTarget::Thread { channel, thread_ts } => {
+                let messages = self
+                    .slack_api
+                    .conversations_replies(channel, thread_ts, self.options.max_messages)
+                    .await?;
+                ("スレッドの要約".to_string(), messages)
+            }
+            SummaryTarget::Channel { channel, hours } => {
+                let oldest = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs().saturating_sub(hours * 60 * 60))
+                    .unwrap_or_default()
+                    .to_string();
+                let messages = self.channel_messages(channel, &oldest).await?;
+                (format!("直近{}時間の要約", hours), messages)
+            }
+        };
+
+        let lines = self.transcript(&messages);
+        if lines.is_empty() {
+            return Ok(format!("*{}*\n要約するメッセージがありません。", title));
+        }
+        self.summarize_lines(&title, &lines, model).await
+    }
+
+    /// 「送信者: 本文」の行を要約する。行が空でないことは呼び出し側で確認する
+    pub async fn summarize_lines(
+        &self,
+        title: &str,
+        lines: &[String],
+        model: &str,
+    ) -> anyhow::Result<String> {
+        let mut chunks = chunk(lines, self.options.chunk_tokens);
+        for _ in 0..MAX_REDUCE_DEPTH {
+            if chunks.len() <= 1 {
+                break;
+            }
+            // 区切りごとにメモを抽出し、メモを次の段の入力にする
+            let mut notes = Vec::with_capacity(chunks.len());
+            for part in &chunks {
+                notes.push(self.complete(MAP_PROMPT, part, model, None).await?);
+            }
+            chunks = chunk(&notes, self.options.chunk_tokens);
+        }
+
+        let input = chunks.join("\n\n");
+        let response = self
+            .complete(
+                REDUCE_PROMPT,
+                &input,
+                model,
+                Some(ResponseFormat::JsonObject),
+            )
+            .await?;
+        match parse_summary(&response) {
+            Some(summary) => Ok(summary.render(title)),
+            // JSONモードに対応していないモデルではそのまま返す
+            None => Ok(format!("*{}*\n{}", title, response.trim())),
+        }
+    }
+
+    // チャンネルの`oldest`以降のメッセージを、スレッドの返信を親の直後に挟んで取得する
+    //
+    // conversations.historyは返信を含まないため、期間内に返信があったスレッドは
+    // conversations.repliesで取り直す(親が期間より前のスレッドは対象外)。
+    // 件数が上限を超えたら新しいものを優先する。
+    async fn channel_messages(
+        &self,
+        channel: &str,
+        oldest: &str,
+    ) -> anyhow::Result<Vec<SlackMessage>> {
+        let max_messages = self.options.max_messages;
+        let since = parse_ts(oldest);
+        let parents = self
+            .slack_api
+            .conversations_history(channel, oldest, max_messages)
+            .await?;
+
+        let mut messages = Vec::with_capacity(parents.len());
+        for parent in parents {
+            let replied = parent.reply_count > 0
+                && parent
+                    .latest_reply
+                    .as_deref()
+                    .is_some_and(|latest| parse_ts(latest) >= since);
+            if !replied {
+                messages.push(parent);
+                continue;
+            }
+
+            let replies = match self
+                .slack_api
+                .conversations_replies(channel, &parent.ts, max_messages)
+                .await
+            {
+                Ok(thread) => thread
+                    .into_iter()
+                    .filter(|m| m.ts != parent.ts && parse_ts(&m.ts) >= since)
+                    .collect(),
+                Err(e) => {
+                    info!("スレッドの返信の取得に失敗: ts={}, {}", parent.ts, e);
+                    Vec::new()
+                }
+            };
+            messages.push(parent);
+            messages.extend(replies);
+        }
+
+        if messages.len() > max_messages {
+            messages.drain(..messages.len() - max_messages);
+        }
+        Ok(messages)
+    }
+
+    // 要約の対象になるメッセージを「送信者: 本文」の行にする
+    fn transcript(&self, messages: &[SlackMessage]) -> Vec<String> {
+        messages
+            .iter()
+            .filter_map(|m| {
+                let text = m.plain_text();
+                if text.trim().is_empty() {
+                    return None;
+                }
+                let sender = if m.is_from(&self.identity) {
+                    "stockmind".to_string()
+                } else {
+                    m.user
+                        .as_deref()
+                        .map(|user| format!("<@{}>", user))
+                        .or_else(|| m.bot_id.clone())
+                        .unwrap_or_else(|| "unknown".to_string())
+                };
+                Some(format!("{}: {}", sender, text.replace('\n', " ")))
+            })
+            .collect()
+    }
+
+    async fn complete(
+        &self,
+        prompt: &str,
+        input: &str,
+        model: &str,
+        response_format: Option<ResponseFormat>,
+    ) -> anyhow::Result<String> {
+        let options = LLMOptions {
+            model,
+            response_format,
+            ..Default::default()
+        };
+        let messages = [ChatMessage::system(prompt), ChatMessage::user(input)];
+        Ok(self
+            .llm_client
+            .get_chat_response(&messages, Some(options))
+            .await?)
+    }
+}
+
+/// メンションの本文が要約の依頼なら対象の期間(時間)を返す
+///
+/// 「要約」「まとめて 6」「summarize 12h」のように書き出しで判定する。
+/// 期間の指定がなければ`Some(None)`を返す。
+pub fn parse_trigger(text: &str) -> Option<Option<u64>> {
+    // 先頭のメンションを取り除く
+    let mut text = text.trim();
+    while let Some(rest) = text.strip_prefix("<@")
+        && let Some(end) = rest.find('>')
+    {
+        text = rest[end + 1..].trim_start();
+    }
+
+    let lower = text.to_lowercase();
+    let word = TRIGGER_WORDS.iter().find(|word| lower.starts_with(*word))?;
+    let rest = lower[word.len()..].trim();
+    let hours = rest
+        .split_whitespace()
+        .next()
+        .and_then(|arg| {
+            arg.trim_end_matches(['h', 'H'])
+                .trim_end_matches("時間")
+                .parse()
+                .ok()
+        })
+        .filter(|hours| *hours > 0);
+    Some(hours)
+}
+
+/// スレッドのパーマリンクから`(channel, thread_ts)`を取り出す
+///
+/// `https://xxx.slack.com/archives/C123/p1700000000123456?thread_ts=1700000000.000100`
+pub fn parse_permalink(url: &str) -> Option<(String, String)> {
+    let url = url.trim_matches(['<', '>']);
+    let path = url.split("/archives/").nth(1)?;
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+    let (channel, message) = path.split_once('/')?;
+    let digits = message.strip_prefix('p')?;
+    if digits.len() <= 6 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let thread_ts = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("thread_ts="))
+        .map(str::to_string)
+        .unwrap_or_else(|| {
+            let (seconds, micros) = digits.split_at(digits.len() - 6);
+            format!("{}.{}", seconds, micros)
+        });
+    Some((channel.to_string(), thread_ts))
+}
+
+fn parse_ts(ts: &str) -> f64 {
+    ts.parse().unwrap_or_default()
+}
+
+/// 行をおおよそのトークン数で区切る。1行で上限を超える場合はその行だけで1つにする
+fn chunk(lines: &[String], max_tokens: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut tokens = 0;
+    for line in lines {
+        let cost = approx_tokens(line);
+        if !current.is_empty() && tokens + cost > max_tokens {
+            chunks.push(std::mem::take(&mut current));
+            tokens = 0;
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(line);
+        tokens += cost;
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+// JSONの前後に説明文やコードブロックが付いていても読めるようにする
+fn parse_summary(response: &str) -> Option<Summary> {
+    let start = response.find('{')?;
+    let end = response.rfind('}')?;
+    serde_json::from_str(response.get(start..=end)?).ok()
+}
+
+/// 要約に失敗したときにユーザーに返すメッセージ
+pub fn failure_message(error: &anyhow::Error) -> String {
+    match error.downcast_ref::<crate::llm::LLMError>() {
+        Some(e) => e.user_message().to_string(),
+        None => "申し訳ありません。会話を取得できなかったため要約できませんでした。".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_trigger_and_hours() {
+        assert_eq!(parse_trigger("<@UBOT> 要約"), Some(None));
+        assert_eq!(parse_trigger("<@UBOT> まとめて 6"), Some(Some(6)));
+        assert_eq!(parse_trigger("Summarize 12h"), Some(Some(12)));
+        assert_eq!(parse_trigger("<@UBOT> <@U1> 要約 3時間"), Some(Some(3)));
+        assert_eq!(parse_trigger("要約 0"), Some(None));
+        assert_eq!(parse_trigger("要約 昨日の分"), Some(None));
+    }
+
+    #[test]
+    fn ignores_messages_that_do_not_start_with_a_trigger() {
+        assert_eq!(parse_trigger("<@UBOT> 今日の議論を要約して"), None);
+        assert_eq!(parse_trigger("こんにちは"), None);
+        assert_eq!(parse_trigger(""), None);
+    }
+
+    #[test]
+    fn parses_permalink() {
+        assert_eq!(
+            parse_permalink("https://example.slack.com/archives/C123/p1700000000123456"),
+            Some(("C123".to_string(), "1700000000.123456".to_string()))
+        );
+        // スレッド内の返信のリンクは親のthread_tsを使う
+        assert_eq!(
+            parse_permalink(
+                "<https://example.slack.com/archives/C123/p1700000000123456?thread_ts=1700000000.000100&cid=C123>"
+            ),
+            Some(("C123".to_string(), "1700000000.000100".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_permalinks() {
+        for url in [
+            "https://example.com/C123/p1700000000123456",
+            "https://example.slack.com/archives/C123",
+            "https://example.slack.com/archives/C123/1700000000123456",
+            "https://example.slack.com/archives/C123/p123456",
+            "https://example.slack.com/archives/C123/p17000000001234ab",
+        ] {
+            assert_eq!(parse_permalink(url), None, "{}", url);
+        }
+    }
+}
@@ -0,0 +1,393 @@
+//! LLMから呼び出せるツール
+//!
+//! OpenAI形式のtool callingに対応する。モデルが`tool_calls`を返したら
+//! 登録済みのツールを実行し、結果を`tool`メッセージとして会話に加えて
+//! 再度問い合わせる。これを最終的な応答が返るか上限回数に達するまで繰り返す。
+
+use crate::llm::{ChatMessage, Completion, LLMClient, LLMError, LLMOptions, ToolCall};
+use crate::retrieval::RetrievalScope;
+use crate::slack_api::SlackApi;
+use crate::store::MessageStore;
+use chrono::Utc;
+use chrono_tz::Tz;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::sync::Arc;
+use tracing::info;
+
+// ツールの結果としてモデルに渡す最大文字数
+const MAX_RESULT_CHARS: usize = 8000;
+
+/// ツールを呼び出したメッセージの場所
+#[derive(Debug, Clone)]
+pub struct ToolContext {
+    pub channel: String,
+    pub ts: String,
+}
+
+/// モデルから呼び出せるツール
+#[async_trait::async_trait]
+pub trait Tool: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// モデルに示すツールの説明
+    fn description(&self) -> &'static str;
+
+    /// 引数のJSON Schema
+    fn parameters(&self) -> Value;
+
+    async fn call(&self, arguments: Value, context: &ToolContext) -> anyhow::Result<Value>;
+}
+
+// 設定ファイルでの指定
+//
+// ```toml
+// [tools]
+// enabled = true
+// max_iterations = 5
+// ```
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToolOptions {
+    /// LLMにツールを使わせるか
+    pub enabled: bool,
+    /// 1つの応答でツールを呼び出す最大回数
+    pub max_iterations: usize,
+}
+
+impl Default for ToolOptions {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_iterations: 5,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ToolRegistry {
+    tools: Vec<Arc<dyn Tool>>,
+    definitions: Vec<Value>,
+    max_iterations: usize,
+}
+
+impl Default for ToolRegistry {
+    fn default() -> Self {
+        Self {
+            tools: Vec::new(),
+            definitions: Vec::new(),
+            max_iterations: ToolOptions::default().max_iterations,
+        }
+    }
+}
+
+impl ToolRegistry {
+    pub fn with_max_iterations(mut self, max_iterations: usize) -> Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    pub fn with_tool(mut self, tool: impl Tool + 'static) -> Self {
+        self.definitions.push(json!({
+            "type": "function",
+            "function": {
+                "name": tool.name(),
+                "description": tool.description(),
+                "parameters": tool.parameters(),
+            },
+        }));
+        self.tools.push(Arc::new(tool));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    /// ツールを使いながら最終的な応答を取得する
+    ///
+    /// 上限回数に達したら、ツールを渡さずに問い合わせて回答をまとめさせる。
+    pub async fn run(
+        &self,
+        llm_client: &LLMClient,
+        mut messages: Vec<ChatMessage>,
+        options: LLMOptions<'_>,
+        context: &ToolContext,
+    ) -> Result<String, LLMError> {
+        for _ in 0..self.max_iterations {
+            let options = LLMOptions {
+                tools: &self.definitions,
+                ..options.clone()
+            };
+            match llm_client
+                .get_chat_completion(&messages, Some(options))
+                .await?
+            {
+                Completion::Message(content) => return Ok(content),
+                Completion::ToolCalls {
+                    content,
+                    tool_calls,
+                } => {
+                    messages.push(ChatMessage::assistant_tool_calls(
+                        content,
+                        tool_calls.clone(),
+                    ));
+                    for call in &tool_calls {
+                        let result = self.execute(call, context).await;
+                        messages.push(ChatMessage::tool(&call.id, result));
+                    }
+                }
+            }
+        }
+
+        info!(
+            "ツール呼び出しが上限({}回)に達したため回答をまとめさせます",
+            self.max_iterations
+        );
+        messages.push(ChatMessage::user(
+            "ツールはこれ以上使えません。ここまでに得た情報で回答してください。",
+        ));
+        llm_client.get_chat_response(&messages, Some(options)).await
+    }
+
+    // ツールを実行し、モデルに返す文字列にする。失敗した場合もエラー内容をモデルに伝える
+    async fn execute(&self, call: &ToolCall, context: &ToolContext) -> String {
+        let Some(tool) = self.tools.iter().find(|t| t.name() == call.function.name) else {
+            return json!({ "error": format!("不明なツールです: {}", call.function.name) })
+                .to_string();
+        };
+        let arguments = if call.function.arguments.trim().is_empty() {
+            json!({})
+        } else {
+            match serde_json::from_str(&call.function.arguments) {
+                Ok(arguments) => arguments,
+                Err(e) => {
+                    return json!({ "error": format!("引数がJSONではありません: {}", e) })
+                        .to_string();
+                }
+            }
+        };
+
+        info!(
+            "ツールを実行: name={}, arguments={}",
+            call.function.name, arguments
+        );
+        let result = match tool.call(arguments, context).await {
+            Ok(result) => result.to_string(),
+            Err(e) => {
+                info!("ツールの実行に失敗: name={}, {}", call.function.name, e);
+                json!({ "error": e.to_string() }).to_string()
+            }
+        };
+        result.chars().take(MAX_RESULT_CHARS).collect()
+    }
+}
+
+// モデルが指定したチャンネルを検索範囲と照らし合わせる。
+// ワークスペース全体が許可されていなければ、呼び出したチャンネル以外は拒否する
+fn scoped_channel<'a>(
+    scope: RetrievalScope,
+    requested: Option<&'a str>,
+    context: &'a ToolContext,
+) -> anyhow::Result<Option<&'a str>> {
+    match (scope, requested) {
+        (RetrievalScope::Workspace, requested) => Ok(requested),
+        (RetrievalScope::Channel, None) => Ok(Some(&context.channel)),
+        (RetrievalScope::Channel, Some(channel)) if channel == context.channel => Ok(Some(channel)),
+        (RetrievalScope::Channel, Some(channel)) => Err(anyhow::anyhow!(
+            "このチャンネル以外のメッセージは参照できません: {}",
+            channel
+        )),
+    }
+}
+
+/// 保存済みのメッセージを全文検索する
+pub struct SearchMessagesTool {
+    pub store: MessageStore,
+    pub bot_user_id: String,
+    /// 検索できる範囲
+    pub scope: RetrievalScope,
+}
+
+#[async_trait::async_trait]
+impl Tool for SearchMessagesTool {
+    fn name(&self) -> &'static str {
+        "search_messages"
+    }
+
+    fn description(&self) -> &'static str {
+        "Slackワークスペースの過去のメッセージを全文検索します。"
+    }
+
+    fn parameters(&self) -> Value {
+        let channel = match self.scope {
+            RetrievalScope::Channel => "検索するチャンネルID。現在のチャンネルのみ指定できる",
+            RetrievalScope::Workspace => "検索するチャンネルID。省略すると全チャンネル",
+        };
+        json!({
+            "type": "object",
+            "properties": {
+                "query": { "type": "string", "description": "検索語" },
+                "channel": { "type": "string", "description": channel },
+                "limit": { "type": "integer", "minimum": 1, "maximum": 20 },
+            },
+            "required": ["query"],
+        })
+    }
+
+    async fn call(&self, arguments: Value, context: &ToolContext) -> anyhow::Result<Value> {
+        let query = arguments["query"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("queryを指定してください"))?;
+        let limit = arguments["limit"].as_u64().unwrap_or(5).clamp(1, 20) as usize;
+        let channel = scoped_channel(self.scope, arguments["channel"].as_str(), context)?;
+        let messages = self
+            .store
+            .search(query, channel, &self.bot_user_id, &context.ts, limit)?;
+        Ok(json!(
+            messages
+                .iter()
+                .map(|m| json!({
+                    "channel": m.channel,
+                    "ts": m.ts,
+                    "thread_ts": m.thread_ts,
+                    "user": m.sender,
+                    "text": m.text,
+                }))
+                .collect::<Vec<_>>()
+        ))
+    }
+}
+
+/// スレッドのメッセージを取得する
+pub struct FetchThreadTool {
+    pub slack_api: SlackApi,
+    /// 取得できる範囲
+    pub scope: RetrievalScope,
+}
+
+#[async_trait::async_trait]
+impl Tool for FetchThreadTool {
+    fn name(&self) -> &'static str {
+        "fetch_thread"
+    }
+
+    fn description(&self) -> &'static str {
+        "Slackのスレッドのメッセージを古い順に取得します。引数を省略すると現在のスレッドを取得します。"
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "channel": { "type": "string", "description": "チャンネルID" },
+                "ts": {
+                    "type": "string",
+                    "description": "スレッドの親メッセージかスレッド内のメッセージのts",
+                },
+            },
+        })
+    }
+
+    async fn call(&self, arguments: Value, context: &ToolContext) -> anyhow::Result<Value> {
+        let channel = scoped_channel(self.scope, arguments["channel"].as_str(), context)?
+            .unwrap_or(&context.channel);
+        let ts = arguments["ts"].as_str().unwrap_or(&context.ts);
+        let messages = self
+            .slack_api
+            .conversations_replies(channel, ts, 100)
+            .await?;
+        Ok(json!(
+            messages
+                .iter()
+                .map(|m| json!({
+                    "ts": m.ts,
+                    "user": m.user.as_deref().or(m.bot_id.as_deref()),
+                    "text": m.plain_text(),
+                }))
+                .collect::<Vec<_>>()
+        ))
+    }
+}
+
+/// 現在の日時を返す
+pub struct CurrentTimeTool;
+
+#[async_trait::async_trait]
+impl Tool for CurrentTimeTool {
+    fn name(&self) -> &'static str {
+        "current_time"
+    }
+
+    fn description(&self) -> &'static str {
+        "現在の日時を返します。"
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "timezone": {
+                    "type": "string",
+                    "description": "IANAタイムゾーン名。省略するとAsia/Tokyo",
+                },
+            },
+        })
+    }
+
+    async fn call(&self, arguments: Value, _context: &ToolContext) -> anyhow::Result<Value> {
+        let name = arguments["timezone"].as_str().unwrap_or("Asia/Tokyo");
+        let tz: Tz = name
+            .parse()
+            .map_err(|_| anyhow::anyhow!("不明なタイムゾーンです: {}", name))?;
+        let now = Utc::now().with_timezone(&tz);
+        Ok(json!({
+            "timezone": name,
+            "datetime": now.to_rfc3339(),
+            "weekday": now.format("%A").to_string(),
+        }))
+    }
+}
+
+/// Slackのユーザー情報を取得する
+pub struct UserLookupTool {
+    pub slack_api: SlackApi,
+}
+
+#[async_trait::async_trait]
+impl Tool for UserLookupTool {
+    fn name(&self) -> &'static str {
+        "lookup_user"
+    }
+
+    fn description(&self) -> &'static str {
+        "SlackのユーザーIDから名前、役職、タイムゾーンなどを取得します。"
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "user": { "type": "string", "description": "ユーザーID(例: U0123456)" },
+            },
+            "required": ["user"],
+        })
+    }
+
+    async fn call(&self, arguments: Value, _context: &ToolContext) -> anyhow::Result<Value> {
+        let user = arguments["user"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("userを指定してください"))?;
+        let info = self.slack_api.users_info(user).await?;
+        let profile = &info["profile"];
+        Ok(json!({
+            "id": info["id"],
+            "name": info["name"],
+            "real_name": profile["real_name"],
+            "display_name": profile["display_name"],
+            "title": profile["title"],
+            "timezone": info["tz"],
+            "is_bot": info["is_bot"],
+        }))
+    }
+}
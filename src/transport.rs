@@ -0,0 +1,138 @@
+//! Slackからイベントを受け取る経路
+//!
+//! 設定の`[slack] transport`で選ぶ。
+//! - `ngrok`(既定): ngrokのトンネル経由で公開する
+//! - `http`: `[server] listen`で直接待ち受ける。`tls_cert`と`tls_key`を指定すると
+//!   HTTPSになる。ロードバランサーやリバースプロキシの背後に置く場合に使う
+//! - `socket`: Socket Modeで接続する。公開URLは不要
+
+use crate::config::{RuntimeConfig, TransportKind, required};
+use crate::socket_mode::SocketModeOptions;
+use anyhow::Context;
+use axum::Router;
+use axum_server::Handle;
+use axum_server::tls_rustls::RustlsConfig;
+use ngrok::prelude::*;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+pub enum Transport {
+    Socket(SocketModeOptions),
+    Http(HttpListener),
+}
+
+/// HTTPでイベントを受け取る場合の待ち受け方法
+pub enum HttpListener {
+    Ngrok {
+        domain: String,
+    },
+    Bind {
+        addr: SocketAddr,
+        tls: Option<TlsFiles>,
+    },
+}
+
+/// PEM形式の証明書と秘密鍵
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl Transport {
+    /// 検証済みの設定から組み立てる
+    pub fn from_config(config: &RuntimeConfig) -> anyhow::Result<Self> {
+        let slack = &config.slack;
+        let server = &config.server;
+        Ok(match slack.transport {
+            TransportKind::Socket => Self::Socket(SocketModeOptions {
+                app_token: slack
+                    .app_token
+                    .as_ref()
+                    .map(|token| token.expose().to_string())
+                    .unwrap_or_default(),
+                url: slack.socket_mode_url.clone(),
+            }),
+            TransportKind::Ngrok => Self::Http(HttpListener::Ngrok {
+                domain: required(&server.ngrok_domain, "NGROK_DOMAIN")?.clone(),
+            }),
+            TransportKind::Http => Self::Http(HttpListener::Bind {
+                addr: server.listen_addr()?,
+                tls: match (&server.tls_cert, &server.tls_key) {
+                    (Some(cert), Some(key)) => Some(TlsFiles {
+                        cert: cert.clone(),
+                        key: key.clone(),
+                    }),
+                    _ => None,
+                },
+            }),
+        })
+    }
+}
+
+impl HttpListener {
+    /// サーバーを起動し、`shutdown`が取り消されたら新しい接続の受け付けを止める
+    ///
+    /// 受け付け済みのリクエストの処理が終わってから戻る。
+    pub async fn serve(self, router: Router, shutdown: CancellationToken) -> anyhow::Result<()> {
+        let service = router.into_make_service_with_connect_info::<SocketAddr>();
+        match self {
+            Self::Ngrok { domain } => {
+                // NGROKトークンを環境変数から読み込んでセッションを接続
+                let mut session = ngrok::Session::builder()
+                    .authtoken_from_env()
+                    .connect()
+                    .await?;
+                // HTTPエンドポイントのトンネルを開始
+                let tun = session.http_endpoint().domain(domain).listen().await?;
+                info!("Tunnel URL: {}", tun.url());
+                axum::Server::builder(tun)
+                    .serve(service)
+                    .with_graceful_shutdown(shutdown.cancelled_owned())
+                    .await?;
+                if let Err(e) = session.close().await {
+                    info!("ngrokのセッションの切断に失敗: {}", e);
+                }
+            }
+            Self::Bind { addr, tls: None } => {
+                info!("サーバーを開始します: http://{}", addr);
+                axum_server::bind(addr)
+                    .handle(graceful_handle(shutdown))
+                    .serve(service)
+                    .await?;
+            }
+            Self::Bind {
+                addr,
+                tls: Some(tls),
+            } => {
+                let config = RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "証明書を読み込めませんでした: cert={}, key={}",
+                            tls.cert.display(),
+                            tls.key.display()
+                        )
+                    })?;
+                info!("サーバーを開始します: https://{}", addr);
+                axum_server::bind_rustls(addr, config)
+                    .handle(graceful_handle(shutdown))
+                    .serve(service)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// `shutdown`が取り消されたら受け付けを止めるaxum_serverのハンドル
+fn graceful_handle(shutdown: CancellationToken) -> Handle {
+    let handle = Handle::new();
+    let graceful = handle.clone();
+    tokio::spawn(async move {
+        shutdown.cancelled().await;
+        graceful.graceful_shutdown(None);
+    });
+    handle
+}
@@ -0,0 +1,392 @@
+//! メッセージ埋め込みのローカルベクトルインデックス
+//!
+//! 外部のベクトルDBを使わず、追記専用のバイナリファイルに埋め込みを保存して
+//! 起動時にメモリへ読み込む。ベクトルは正規化して保持するため、
+//! コサイン類似度は内積で求まる。
+
+use crate::llm::LLMClient;
+use crate::rate_limit::WorkQueue;
+use anyhow::{Context, anyhow, bail};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+// 検索結果の1件
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub channel: String,
+    pub ts: String,
+    pub score: f32,
+}
+
+struct Entry {
+    channel: String,
+    ts: String,
+    vector: Vec<f32>,
+}
+
+struct Inner {
+    file: File,
+    entries: Vec<Entry>,
+    // (channel, ts) -> entriesの位置
+    positions: HashMap<(String, String), usize>,
+}
+
+#[derive(Clone)]
+pub struct VectorIndex {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl VectorIndex {
+    /// インデックスファイルを開いて読み込む。存在しなければ作成する
+    ///
+    /// 書き込み途中で終了して末尾のレコードが欠けている場合は、
+    /// 最後の完全なレコードまでで切り詰める。
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("ベクトルインデックスを開けません: {}", path.display()))?;
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)
+            .context("ベクトルインデックスの読み込みに失敗しました")?;
+
+        let mut entries: Vec<Entry> = Vec::new();
+        let mut positions = HashMap::new();
+        let mut reader = RecordReader {
+            bytes: &bytes,
+            pos: 0,
+        };
+        let mut valid_len = 0;
+        while let Some(entry) = reader.next_record() {
+            valid_len = reader.pos;
+            let key = (entry.channel.clone(), entry.ts.clone());
+            match positions.get(&key) {
+                Some(&i) => entries[i] = entry,
+                None => {
+                    positions.insert(key, entries.len());
+                    entries.push(entry);
+                }
+            }
+        }
+        if valid_len < bytes.len() {
+            file.set_len(valid_len as u64)
+                .context("壊れたレコードの切り詰めに失敗しました")?;
+        }
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Inner {
+                file,
+                entries,
+                positions,
+            })),
+        })
+    }
+
+    fn inner(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner().entries.len()
+    }
+
+    pub fn contains(&self, channel: &str, ts: &str) -> bool {
+        self.inner()
+            .positions
+            .contains_key(&(channel.to_string(), ts.to_string()))
+    }
+
+    /// ベクトルを追加する。同じメッセージが既にあれば置き換える
+    pub fn insert(&self, channel: &str, ts: &str, vector: Vec<f32>) -> anyhow::Result<()> {
+        let vector = normalize(vector).ok_or_else(|| anyhow!("ゼロベクトルは登録できません"))?;
+
+        let mut inner = self.inner();
+        if let Some(first) = inner.entries.first()
+            && first.vector.len() != vector.len()
+        {
+            bail!(
+                "次元数が一致しません: index={}, vector={}",
+                first.vector.len(),
+                vector.len()
+            );
+        }
+
+        let entry = Entry {
+            channel: channel.to_string(),
+            ts: ts.to_string(),
+            vector,
+        };
+        inner
+            .file
+            .write_all(&encode_record(&entry))
+            .context("ベクトルインデックスへの書き込みに失敗しました")?;
+
+        let key = (entry.channel.clone(), entry.ts.clone());
+        match inner.positions.get(&key).copied() {
+            Some(i) => inner.entries[i] = entry,
+            None => {
+                let i = inner.entries.len();
+                inner.positions.insert(key, i);
+                inner.entries.push(entry);
+            }
+        }
+        Ok(())
+    }
+
+    /// コサイン類似度の高い順に最大`k`件を返す。`channel`を指定するとそのチャンネルに限定する
+    pub fn search(&self, query: Vec<f32>, k: usize, channel: Option<&str>) -> Vec<SearchHit> {
+        let Some(query) = normalize(query) else {
+            return Vec::new();
+        };
+
+        let inner = self.inner();
+        let mut hits: Vec<SearchHit> = inner
+            .entries
+            .iter()
+            .filter(|e| e.vector.len() == query.len())
+            .filter(|e| channel.is_none_or(|c| e.channel == c))
+            .map(|e| SearchHit {
+                channel: e.channel.clone(),
+                ts: e.ts.clone(),
+                score: dot(&e.vector, &query),
+            })
+            .collect();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+        hits.truncate(k);
+        hits
+    }
+}
+
+// 埋め込みの取得とインデックスへの登録・検索をまとめたもの
+#[derive(Clone)]
+pub struct SemanticIndex {
+    llm_client: LLMClient,
+    model: String,
+    index: VectorIndex,
+    queue: WorkQueue,
+}
+
+impl SemanticIndex {
+    pub fn new(llm_client: LLMClient, model: String, index: VectorIndex) -> Self {
+        Self {
+            llm_client,
+            model,
+            index,
+            queue: WorkQueue::default(),
+        }
+    }
+
+    /// 埋め込みの取得をLLMの呼び出しと同じ待ち行列に並ばせる
+    pub fn with_queue(mut self, queue: WorkQueue) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    async fn embed(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+        let slot = self
+            .queue
+            .try_enqueue()
+            .ok_or_else(|| anyhow!("待ち行列が満杯のため埋め込みを取得できません"))?;
+        let _permit = slot.start().await;
+        let mut embeddings = self
+            .llm_client
+            .get_embeddings(&[text.to_string()], &self.model)
+            .await
+            .map_err(|e| anyhow!("埋め込みの取得に失敗しました: {}", e))?;
+        embeddings
+            .pop()
+            .ok_or_else(|| anyhow!("埋め込みが返されませんでした"))
+    }
+
+    /// 受信したメッセージをインデックスに追加する。登録済みなら何もしない
+    pub async fn add(&self, channel: &str, ts: &str, text: &str) -> anyhow::Result<()> {
+        if text.trim().is_empty() || self.index.contains(channel, ts) {
+            return Ok(());
+        }
+        let vector = self.embed(text).await?;
+        self.index.insert(channel, ts, vector)
+    }
+
+    /// 意味的に近いメッセージを検索する
+    pub async fn search(
+        &self,
+        query: &str,
+        k: usize,
+        channel: Option<&str>,
+    ) -> anyhow::Result<Vec<SearchHit>> {
+        let vector = self.embed(query).await?;
+        Ok(self.index.search(vector, k, channel))
+    }
+}
+
+fn normalize(mut vector: Vec<f32>) -> Option<Vec<f32>> {
+    let norm = dot(&vector, &vector).sqrt();
+    if norm == 0.0 || !norm.is_finite() {
+        return None;
+    }
+    vector.iter_mut().for_each(|v| *v /= norm);
+    Some(vector)
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+// レコード形式(リトルエンディアン):
+// [u32 channel長][channel][u32 ts長][ts][u32 次元数][f32 * 次元数]
+fn encode_record(entry: &Entry) -> Vec<u8> {
+    let mut buf =
+        Vec::with_capacity(12 + entry.channel.len() + entry.ts.len() + entry.vector.len() * 4);
+    for s in [&entry.channel, &entry.ts] {
+        buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+        buf.extend_from_slice(s.as_bytes());
+    }
+    buf.extend_from_slice(&(entry.vector.len() as u32).to_le_bytes());
+    for v in &entry.vector {
+        buf.extend_from_slice(&v.to_le_bytes());
+    }
+    buf
+}
+
+struct RecordReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl RecordReader<'_> {
+    fn take(&mut self, len: usize) -> Option<&[u8]> {
+        let end = self.pos.checked_add(len)?;
+        let slice = self.bytes.get(self.pos..end)?;
+        self.pos = end;
+        Some(slice)
+    }
+
+    fn u32(&mut self) -> Option<usize> {
+        let bytes = self.take(4)?;
+        Some(u32::from_le_bytes(bytes.try_into().ok()?) as usize)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u32()?;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+
+    // 完全なレコードを読めなければNoneを返し、位置は進めない
+    fn next_record(&mut self) -> Option<Entry> {
+        let start = self.pos;
+        let entry = (|| {
+            let channel = self.string()?;
+            let ts = self.string()?;
+            let dim = self.u32()?;
+            let raw = self.take(dim.checked_mul(4)?)?;
+            let vector = raw
+                .chunks_exact(4)
+                .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                .collect();
+            Some(Entry {
+                channel,
+                ts,
+                vector,
+            })
+        })();
+        if entry.is_none() {
+            self.pos = start;
+        }
+        entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // テストごとに別のファイルを使い、終了時に削除する
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "stockmind-{}-{}.vectors",
+                name,
+                std::process::id()
+            ));
+            std::fs::remove_file(&path).ok();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            std::fs::remove_file(&self.0).ok();
+        }
+    }
+
+    #[test]
+    fn recovers_from_truncated_record() {
+        let path = TempPath::new("truncated");
+        let index = VectorIndex::open(&path.0).unwrap();
+        index.insert("C1", "1.0", vec![1.0, 0.0]).unwrap();
+        index.insert("C1", "2.0", vec![0.0, 1.0]).unwrap();
+        drop(index);
+        let valid_len = std::fs::metadata(&path.0).unwrap().len();
+
+        // 書き込み途中で終了した状態を再現する
+        let partial = encode_record(&Entry {
+            channel: "C1".to_string(),
+            ts: "3.0".to_string(),
+            vector: vec![1.0, 1.0],
+        });
+        OpenOptions::new()
+            .append(true)
+            .open(&path.0)
+            .unwrap()
+            .write_all(&partial[..partial.len() - 3])
+            .unwrap();
+
+        let index = VectorIndex::open(&path.0).unwrap();
+        assert_eq!(index.len(), 2);
+        assert!(!index.contains("C1", "3.0"));
+        assert_eq!(std::fs::metadata(&path.0).unwrap().len(), valid_len);
+
+        // 切り詰めた後に追記したレコードは読み直せる
+        index.insert("C1", "3.0", vec![1.0, 1.0]).unwrap();
+        drop(index);
+        let index = VectorIndex::open(&path.0).unwrap();
+        assert_eq!(index.len(), 3);
+        assert!(index.contains("C1", "3.0"));
+    }
+
+    #[test]
+    fn replaces_existing_entry_and_searches_by_similarity() {
+        let path = TempPath::new("search");
+        let index = VectorIndex::open(&path.0).unwrap();
+        index.insert("C1", "1.0", vec![1.0, 0.0]).unwrap();
+        index.insert("C1", "2.0", vec![0.0, 1.0]).unwrap();
+        index.insert("C2", "3.0", vec![1.0, 0.1]).unwrap();
+        index.insert("C1", "2.0", vec![0.6, 0.8]).unwrap();
+        assert!(index.insert("C1", "4.0", vec![1.0, 0.0, 0.0]).is_err());
+        assert!(index.insert("C1", "5.0", vec![0.0, 0.0]).is_err());
+        drop(index);
+
+        let index = VectorIndex::open(&path.0).unwrap();
+        assert_eq!(index.len(), 3);
+        let hits = index.search(vec![1.0, 0.0], 2, None);
+        let keys: Vec<_> = hits
+            .iter()
+            .map(|h| (h.channel.as_str(), h.ts.as_str()))
+            .collect();
+        assert_eq!(keys, [("C1", "1.0"), ("C2", "3.0")]);
+
+        let hits = index.search(vec![0.0, 1.0], 10, Some("C1"));
+        let keys: Vec<_> = hits.iter().map(|h| h.ts.as_str()).collect();
+        assert_eq!(keys, ["2.0", "1.0"]);
+    }
+}